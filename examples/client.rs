@@ -0,0 +1,46 @@
+use anyhow::Context;
+use enet::{
+    host::{config::HostConfig, Host},
+    peer::{Packet, PeerRecvEvent},
+    protocol::PacketFlags,
+};
+use std::net::{Ipv4Addr, SocketAddr};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let cli_config = HostConfig::new(1)?;
+    let mut cli_host = Host::create_from_address(
+        cli_config,
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+    )
+    .await?;
+
+    let mut cli_peer = cli_host
+        .connect(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9001), 2, 0)
+        .await
+        .context("connect failed")?;
+
+    tracing::info!("Connected to server, sending hello");
+    cli_peer
+        .send(Packet {
+            data: b"hello".to_vec(),
+            channel: 0,
+            flags: PacketFlags::reliable(),
+        })
+        .await?;
+
+    loop {
+        tokio::select! {
+            e = cli_peer.poll() => {
+                tracing::info!("Got peer event: {e:?}");
+                if let PeerRecvEvent::Disconnect(_) = e {break}
+            }
+            e = cli_host.poll() => {
+                tracing::info!("Got host event: {e:?}");
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -58,7 +58,7 @@
 //                             send_msg(&mut peer_1, p.clone()).await;
 //                             send_msg(&mut peer_2, p).await;
 //                         }
-//                         PeerRecvEvent::Disconnect => return Ok::<(), ENetError>(()),
+//                         PeerRecvEvent::Disconnect(_) => return Ok::<(), ENetError>(()),
 //                     }
 //                 },
 //                 packet = peer_2.poll() => {
@@ -67,7 +67,7 @@
 //                             send_msg(&mut peer_1, p.clone()).await;
 //                             send_msg(&mut peer_2, p).await;
 //                         }
-//                         PeerRecvEvent::Disconnect => return Ok::<(), ENetError>(()),
+//                         PeerRecvEvent::Disconnect(_) => return Ok::<(), ENetError>(()),
 //                     }
 //                 }
 //             }
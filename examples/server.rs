@@ -28,7 +28,7 @@ async fn main() -> anyhow::Result<()> {
         tokio::select! {
             e = serv_peer.poll() => {
                 tracing::info!("Got peer event: {e:?}");
-                if let PeerRecvEvent::Disconnect = e {break}
+                if let PeerRecvEvent::Disconnect(_) = e {break}
             }
             e = serv_host.poll() => {
                 tracing::info!("Got host event: {e:?}");
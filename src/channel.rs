@@ -1,3 +1,14 @@
+use std::collections::HashMap;
+
+use crate::{
+    consts::{
+        HOST_DEFAULT_MAXIMUM_PACKET_SIZE, PEER_FREE_RELIABLE_WINDOWS, PEER_RELIABLE_WINDOWS,
+        PEER_RELIABLE_WINDOW_SIZE, PROTOCOL_MAXIMUM_FRAGMENT_COUNT,
+    },
+    error::{ENetError, Result},
+    protocol::Command,
+};
+
 /// An ID to identify the channel with
 pub type ChannelID = u16;
 
@@ -9,4 +20,201 @@ pub struct Channel {
 
     pub incoming_reliable_sequence_number: u16,
     pub incoming_unreliable_sequence_number: u16,
+
+    /// Reliable commands that arrived ahead of a missing one
+    pub(crate) incoming_reliable_commands: HashMap<u16, Command>,
+    /// Unreliable commands sent after a reliable one that has yet to arrive, keyed by reliable
+    /// and unreliable sequence number
+    pub(crate) incoming_unreliable_commands: HashMap<(u16, u16), Command>,
+
+    /// Packets being reassembled, keyed by their start sequence number
+    pub(crate) incoming_fragments: HashMap<u16, FragmentedPacket>,
+    /// Unreliable packets being reassembled, keyed by reliable and start sequence number
+    pub(crate) incoming_unreliable_fragments: HashMap<(u16, u16), FragmentedPacket>,
+}
+
+/// Where a reliable sequence number falls relative to a channel's receive window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReliableWindow {
+    /// The command can be queued for delivery
+    Accept,
+    /// The command was already delivered or wrapped behind the window, it is
+    /// acknowledged but dropped
+    Discard,
+    /// The command is too far ahead to acknowledge or queue
+    Ahead,
+}
+
+impl Channel {
+    /// Checks a reliable sequence number against the window of commands that can be held
+    pub(crate) fn reliable_window(&self, seq: u16) -> ReliableWindow {
+        let current = self.incoming_reliable_sequence_number;
+        if seq == current || self.incoming_reliable_commands.contains_key(&seq) {
+            return ReliableWindow::Discard;
+        }
+
+        let mut window = (seq as usize) / PEER_RELIABLE_WINDOW_SIZE;
+        let current_window = (current as usize) / PEER_RELIABLE_WINDOW_SIZE;
+        if seq < current {
+            window += PEER_RELIABLE_WINDOWS;
+        }
+
+        let free_end = current_window + PEER_FREE_RELIABLE_WINDOWS - 1;
+        if window < free_end {
+            return ReliableWindow::Accept;
+        }
+
+        // Just past the free windows is treated as ahead, anything further has wrapped around
+        if window <= free_end + 1 {
+            ReliableWindow::Ahead
+        } else {
+            ReliableWindow::Discard
+        }
+    }
+
+    /// Whether an unreliable command can be delivered now, which it can if it is newer than
+    /// the last one delivered, older and repeated ones are dropped as in ENet. Commands sent
+    /// after a reliable one that has yet to arrive are held until it is delivered
+    pub(crate) fn accept_unreliable(
+        &mut self,
+        command: &Command,
+        unreliable_sequence: u16,
+    ) -> bool {
+        let reliable_sequence = command.info.reliable_sequence_number;
+        let current = (
+            self.incoming_reliable_sequence_number,
+            self.incoming_unreliable_sequence_number,
+        );
+        if !is_older(current, (reliable_sequence, unreliable_sequence)) {
+            return false;
+        }
+        if reliable_sequence == current.0 {
+            self.incoming_unreliable_sequence_number = unreliable_sequence;
+            return true;
+        }
+
+        // Dropped if the reliable command is beyond the window, it would never be delivered
+        if self
+            .incoming_reliable_commands
+            .contains_key(&reliable_sequence)
+            || self.reliable_window(reliable_sequence) == ReliableWindow::Accept
+        {
+            self.incoming_unreliable_commands
+                .entry((reliable_sequence, unreliable_sequence))
+                .or_insert_with(|| command.clone());
+        }
+        false
+    }
+
+    /// Takes the held unreliable commands that follow the reliable command just delivered, in
+    /// order, and drops those left behind
+    pub(crate) fn release_unreliable(&mut self) -> Vec<Command> {
+        let current = self.incoming_reliable_sequence_number;
+        self.incoming_unreliable_commands
+            .retain(|&(r, _), _| !sequence_before(r, current));
+
+        let mut ready: Vec<_> = self
+            .incoming_unreliable_commands
+            .keys()
+            .filter(|&&(r, _)| r == current)
+            .copied()
+            .collect();
+        // Unreliable sequences restart from zero after every reliable command
+        ready.sort_unstable();
+        if let Some(&(_, last)) = ready.last() {
+            self.incoming_unreliable_sequence_number = last;
+        }
+        ready
+            .iter()
+            .filter_map(|key| self.incoming_unreliable_commands.remove(key))
+            .collect()
+    }
+
+    /// Drops unreliable fragment groups older than the given sequence numbers
+    pub(crate) fn discard_stale_fragments(
+        &mut self,
+        reliable_sequence: u16,
+        unreliable_sequence: u16,
+    ) {
+        self.incoming_unreliable_fragments
+            .retain(|&(r, s), _| !is_older((r, s), (reliable_sequence, unreliable_sequence)));
+    }
+
+    /// Whether a newer unreliable fragment group is already being reassembled
+    pub(crate) fn has_newer_fragments(
+        &self,
+        reliable_sequence: u16,
+        unreliable_sequence: u16,
+    ) -> bool {
+        self.incoming_unreliable_fragments
+            .keys()
+            .any(|&k| is_older((reliable_sequence, unreliable_sequence), k))
+    }
+}
+
+/// Whether sequence `a` comes before `b`, accounting for wraparound
+pub(crate) fn sequence_before(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) < 0
+}
+
+/// Orders unreliable sequences by their reliable sequence, then their own
+fn is_older(a: (u16, u16), b: (u16, u16)) -> bool {
+    sequence_before(a.0, b.0) || (a.0 == b.0 && sequence_before(a.1, b.1))
+}
+
+/// A packet being rebuilt from its fragments
+#[derive(Debug)]
+pub(crate) struct FragmentedPacket {
+    pub(crate) data: Vec<u8>,
+    fragment_count: u32,
+    /// Bitset of the fragments already received
+    received: Vec<u32>,
+    remaining: u32,
+}
+
+impl FragmentedPacket {
+    pub fn new(fragment_count: u32, total_length: u32) -> Result<Self> {
+        if fragment_count == 0
+            || fragment_count as usize > PROTOCOL_MAXIMUM_FRAGMENT_COUNT
+            || total_length as usize > HOST_DEFAULT_MAXIMUM_PACKET_SIZE
+        {
+            return Err(ENetError::InvalidPacket());
+        }
+
+        Ok(Self {
+            data: vec![0; total_length as usize],
+            fragment_count,
+            received: vec![0; (fragment_count as usize).div_ceil(32)],
+            remaining: fragment_count,
+        })
+    }
+
+    /// Copies a fragment into the packet, returning true once every fragment arrived
+    pub fn insert(
+        &mut self,
+        fragment_count: u32,
+        fragment_number: u32,
+        total_length: u32,
+        fragment_offset: u32,
+        data: &[u8],
+    ) -> Result<bool> {
+        let offset = fragment_offset as usize;
+        if fragment_count != self.fragment_count
+            || total_length as usize != self.data.len()
+            || fragment_number >= fragment_count
+            || offset >= self.data.len()
+            || data.len() > self.data.len() - offset
+        {
+            return Err(ENetError::InvalidPacket());
+        }
+
+        let (word, bit) = ((fragment_number / 32) as usize, fragment_number % 32);
+        if self.received[word] & (1 << bit) == 0 {
+            self.received[word] |= 1 << bit;
+            self.remaining -= 1;
+            self.data[offset..offset + data.len()].copy_from_slice(data);
+        }
+
+        Ok(self.remaining == 0)
+    }
 }
@@ -1,18 +1,68 @@
 /// Minium amount of data within a single udp packet
-const PROTOCOL_MINIMUM_MTU: usize = 576;
+pub const PROTOCOL_MINIMUM_MTU: usize = 576;
 /// Maximum amount of data within a single udp packet
-const PROTOCOL_MAXIMUM_MTU: usize = 4096;
+pub const PROTOCOL_MAXIMUM_MTU: usize = 4096;
 /// Maximum possible commands within one udp packet
-const PROTOCOL_MAXIMUM_PACKET_COMMANDS: usize = 32;
+pub const PROTOCOL_MAXIMUM_PACKET_COMMANDS: usize = 32;
 /// Minimum allowed window size
-const PROTOCOL_MINIMUM_WINDOW_SIZE: usize = 4096;
+pub const PROTOCOL_MINIMUM_WINDOW_SIZE: usize = 4096;
 /// Maximum allowed window size
-const PROTOCOL_MAXIMUM_WINDOW_SIZE: usize = 65536;
+pub const PROTOCOL_MAXIMUM_WINDOW_SIZE: usize = 65536;
 /// Minimium allowed channel count
-const PROTOCOL_MINIMUM_CHANNEL_COUNT: usize = 1;
+pub const PROTOCOL_MINIMUM_CHANNEL_COUNT: usize = 1;
 /// Maximum allowed channel count
-const PROTOCOL_MAXIMUM_CHANNEL_COUNT: usize = 255;
+pub const PROTOCOL_MAXIMUM_CHANNEL_COUNT: usize = 255;
 /// Maximum allowed peer id
-const PROTOCOL_MAXIMUM_PEER_ID: usize = 0xFFF;
+pub const PROTOCOL_MAXIMUM_PEER_ID: usize = 0xFFF;
 /// Maximum allowed fragmentation count
-const PROTOCOL_MAXIMUM_FRAGMENT_COUNT: usize = 1024 * 1024;
+pub const PROTOCOL_MAXIMUM_FRAGMENT_COUNT: usize = 1024 * 1024;
+
+/// Default mtu advertised when connecting to another host
+pub const HOST_DEFAULT_MTU: usize = 1400;
+/// Interval (ms) between recalculating how bandwidth is shared across peers
+pub const HOST_BANDWIDTH_THROTTLE_INTERVAL: u64 = 1000;
+/// Number of connects that can wait on their cookie at once, a connect takes over the slot of
+/// any earlier one hashing to the same slot
+pub const HOST_CONNECT_COOKIE_SLOTS: usize = 1024;
+/// Interval (ms) connect cookies are made in, a cookie stays valid until the end of the next
+pub const HOST_CONNECT_COOKIE_INTERVAL: u64 = 5000;
+/// Largest packet that will be reassembled from fragments
+pub const HOST_DEFAULT_MAXIMUM_PACKET_SIZE: usize = 32 * 1024 * 1024;
+
+/// Bandwidth (bytes/sec) that maps to one minimum window size
+pub const PEER_WINDOW_SIZE_SCALE: usize = 64 * 1024;
+/// Number of reliable windows the sequence space is split into
+pub const PEER_RELIABLE_WINDOWS: usize = 16;
+/// Number of reliable sequence numbers in a single window
+pub const PEER_RELIABLE_WINDOW_SIZE: usize = 0x1000;
+/// Number of windows ahead a reliable command may be held
+pub const PEER_FREE_RELIABLE_WINDOWS: usize = 8;
+/// Number of unsequenced groups tracked by a single window
+pub const PEER_UNSEQUENCED_WINDOW_SIZE: usize = 1024;
+/// Number of windows ahead an unsequenced group may be before it is dropped
+pub const PEER_FREE_UNSEQUENCED_WINDOWS: usize = 32;
+/// Throttle value at which every unreliable packet is sent
+pub const PEER_PACKET_THROTTLE_SCALE: u32 = 32;
+/// Step the throttle counter advances by for every unreliable packet
+pub const PEER_PACKET_THROTTLE_COUNTER: u32 = 7;
+/// Round trip time (ms) assumed until a peer's first acknowledgement
+pub const PEER_DEFAULT_ROUND_TRIP_TIME: u64 = 500;
+/// Throttle value a new peer starts with
+pub const PEER_DEFAULT_PACKET_THROTTLE: u32 = 32;
+/// Default interval (ms) between throttle updates
+pub const PEER_PACKET_THROTTLE_INTERVAL: u32 = 5000;
+/// Default throttle acceleration
+pub const PEER_PACKET_THROTTLE_ACCELERATION: u32 = 2;
+/// Default throttle deceleration
+pub const PEER_PACKET_THROTTLE_DECELERATION: u32 = 2;
+/// Packet loss value at which every reliable command is lost
+pub const PEER_PACKET_LOSS_SCALE: u32 = 1 << 16;
+/// Interval (ms) between packet loss updates
+pub const PEER_PACKET_LOSS_INTERVAL: u64 = 10000;
+/// Default multiple of a command's first retransmit timeout it may back off to before its
+/// peer can time out
+pub const PEER_TIMEOUT_LIMIT: u32 = 32;
+/// Default time (ms) a peer must go unacknowledged before timing out at the timeout limit
+pub const PEER_TIMEOUT_MINIMUM: u64 = 5000;
+/// Default time (ms) after which an unacknowledged peer always times out
+pub const PEER_TIMEOUT_MAXIMUM: u64 = 30000;
@@ -1,4 +1,5 @@
 use serde::{de::Error as DeError, ser::Error as SerError};
+use std::net::SocketAddr;
 use std::num::TryFromIntError;
 use std::str::Utf8Error;
 
@@ -46,9 +47,21 @@ pub enum ENetError {
     #[error("Invalid packet received")]
     InvalidPacket(),
 
+    #[error("Packet too large: {0} bytes")]
+    PacketTooLarge(usize),
+
     #[error("Invalid peer id: {0}")]
     InvalidPeerId(PeerID),
 
+    #[error("Every peer slot is in use")]
+    NoFreePeers,
+
+    #[error("Invalid connect: {0}")]
+    InvalidConnect(#[from] ConnectError),
+
+    #[error("Failed to connect to {0}")]
+    ConnectFailed(SocketAddr),
+
     #[error("Invalid channel id: {0}")]
     InvalidChannelId(ChannelID),
 
@@ -65,10 +78,20 @@ impl From<ChannelError> for ENetError {
     }
 }
 
+/// Why a connect from a remote host was rejected
+#[derive(Error, Debug)]
+pub enum ConnectError {
+    #[error("channel count {0} is outside the protocol limits")]
+    ChannelCount(u32),
+
+    #[error("peer id {0} is outside the protocol limits")]
+    PeerId(u16),
+}
+
 /// An error that happens during encoding
 #[derive(Error, Debug)]
 pub enum EncodingError {
-    #[error("Not enough data, {0} < {0}")]
+    #[error("Not enough data, {0} < {1}")]
     NotEnoughData(usize, usize),
     #[error("Invalid string data")]
     BadUtf8(#[from] Utf8Error),
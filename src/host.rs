@@ -1,13 +1,18 @@
+pub mod accept;
 pub mod config;
+mod handshake;
 pub mod hostevents;
+pub mod stats;
 
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    hash::RandomState,
     net::SocketAddr,
     str::FromStr,
     time::{Duration, Instant},
 };
 
+use random::Source;
 use tokio::{
     net::{ToSocketAddrs, UdpSocket},
     select,
@@ -15,21 +20,35 @@ use tokio::{
 };
 
 use self::{
+    accept::{ConnectDecision, ConnectPolicy, ConnectRequest},
     config::HostConfig,
+    handshake::CookieConnect,
     hostevents::{HostPollEvent, HostRecvEvent, HostSendEvent},
+    stats::HostStats,
 };
 
 use crate::{
-    channel::{Channel, ChannelID},
-    error::{ChannelError, ENetError, Result},
+    channel::{sequence_before, ChannelID, FragmentedPacket, ReliableWindow},
+    consts::{
+        HOST_BANDWIDTH_THROTTLE_INTERVAL, PEER_PACKET_THROTTLE_SCALE,
+        PROTOCOL_MAXIMUM_CHANNEL_COUNT, PROTOCOL_MAXIMUM_FRAGMENT_COUNT, PROTOCOL_MAXIMUM_MTU,
+        PROTOCOL_MAXIMUM_PEER_ID, PROTOCOL_MAXIMUM_WINDOW_SIZE, PROTOCOL_MINIMUM_CHANNEL_COUNT,
+        PROTOCOL_MINIMUM_MTU, PROTOCOL_MINIMUM_WINDOW_SIZE,
+    },
+    error::{ChannelError, ConnectError, ENetError, Result},
     net::{
-        socket::{ENetSocket, Socket},
+        compress::RangeCoder,
+        socket::{command_size, ENetSocket, Socket},
         time::PacketTime,
     },
-    peer::{Packet, Peer, PeerID, PeerInfo, PeerRecvEvent},
+    peer::{
+        bandwidth_window_size, DisconnectReason, Packet, Peer, PeerID, PeerInfo, PeerRecvEvent,
+        PeerSendEvent, PeerState, PeerStats,
+    },
     protocol::{
-        AcknowledgeCommand, Command, CommandInfo, ConnectCommand, DisconnectCommand, PacketFlags,
-        PingCommand, ProtocolCommand, VerifyConnectCommand,
+        AcknowledgeCommand, BandwidthLimitCommand, Command, CommandInfo, ConnectCommand,
+        DisconnectCommand, PacketFlags, PingCommand, ProtocolCommand, SendFragmentCommand,
+        SendUnreliableFragmentCommand, VerifyConnectCommand,
     },
 };
 
@@ -39,12 +58,26 @@ pub struct Host<S: Socket = ENetSocket> {
     pub peers: HashMap<PeerID, PeerInfo>,
     pub config: HostConfig,
 
-    // bandwidth_throttle_epoch: u32,
+    bandwidth_throttle_epoch: Duration,
+    recalculate_bandwidth_limits: bool,
     // mtu: u32,
     pub random: random::Default,
 
-    pub next_peer: u16,
+    /// Incoming and outgoing session ids each peer id last used, kept after the peer is
+    /// removed so a reconnect starts a new session
+    session_ids: HashMap<PeerID, (u16, u16)>,
     unack_packets: HashMap<(PeerID, ChannelID, u16), UnAckPacket>,
+    /// Commands waiting to be packed into datagrams
+    outgoing_commands: Vec<Command>,
+    pending_events: VecDeque<HostPollEvent>,
+    /// Decides which incoming connects are accepted, all of them when unset
+    connect_policy: Option<Box<dyn ConnectPolicy>>,
+    /// Connects waiting on their cookie, in slots picked by the remote's address
+    cookie_connects: Vec<Option<CookieConnect>>,
+    /// Key of the hash connect cookies are made from
+    cookie_key: RandomState,
+    /// Counters kept by the host itself, the socket keeps its own
+    stats: HostStats,
 
     pub receiver: Receiver<HostRecvEvent>,
 
@@ -60,18 +93,21 @@ struct UnAckPacket {
     command: Command,
     /// Duration time sent
     last_sent: Duration,
-    /// How many retries the packet attempted
-    retries: usize,
+    /// How long to wait on an acknowledgement before resending, doubled on every resend
+    round_trip_timeout: Duration,
+    /// Once the timeout backed off this far the peer may time out
+    round_trip_timeout_limit: Duration,
     /// The peer the command was being sent to
     peer_id: PeerID,
 }
 
 impl UnAckPacket {
-    pub fn new(command: Command) -> Self {
+    pub fn new(command: Command, round_trip_timeout: Duration, timeout_limit: u32) -> Self {
         Self {
             last_sent: command.info.sent_time,
             peer_id: command.info.internal_peer_id,
-            retries: 0,
+            round_trip_timeout,
+            round_trip_timeout_limit: round_trip_timeout * timeout_limit,
             command,
         }
     }
@@ -83,7 +119,7 @@ impl Host {
         Host::<ENetSocket>::create::<ENetSocket>(config, socket)
     }
 
-    pub fn create<S: Socket>(config: HostConfig, socket: impl Into<S>) -> Result<Host<S>> {
+    pub fn create<S: Socket>(mut config: HostConfig, socket: impl Into<S>) -> Result<Host<S>> {
         // let addr = socket.local_addr().unwrap();
         let addr = SocketAddr::from_str("127.0.0.1:8080").unwrap();
         let random = random::default(10);
@@ -95,15 +131,33 @@ impl Host {
         // TODO Set default peers ... maybe
         let (from_cli_tx, from_cli_rx) = tokio::sync::mpsc::channel(100);
 
+        config.mtu = config.mtu.clamp(PROTOCOL_MINIMUM_MTU, PROTOCOL_MAXIMUM_MTU);
+
+        let mut socket: S = socket.into();
+        if config.range_coder {
+            socket.set_compressor(Box::new(RangeCoder::new()));
+        }
+        if config.checksum {
+            socket.enable_checksum();
+        }
+
         Ok(Host {
-            socket: socket.into(),
+            socket,
             peers,
             config,
             random,
             from_cli_tx,
             receiver: from_cli_rx,
-            next_peer: 0,
+            session_ids: Default::default(),
             unack_packets: Default::default(),
+            outgoing_commands: Default::default(),
+            bandwidth_throttle_epoch: Duration::ZERO,
+            recalculate_bandwidth_limits: false,
+            pending_events: Default::default(),
+            connect_policy: None,
+            cookie_connects: Vec::new(),
+            cookie_key: RandomState::new(),
+            stats: HostStats::default(),
             bound_socket_addr: addr,
         })
     }
@@ -113,87 +167,194 @@ impl Host {
         addr: SocketAddr,
         connect: &ConnectCommand,
     ) -> Result<(Peer, VerifyConnectCommand)> {
-        // TODO Check channel count to consts
-        // TODO Check MTU to consts
+        let validated = self.validate_connect(connect)?;
+        let peer_id = self.free_peer_id()?;
+        self.accept_connect(peer_id, addr, connect, &validated)
+    }
 
-        let channel_count: usize = connect.channel_count.try_into()?;
-        let channel_count = channel_count.min(self.config.peer_count);
+    /// Adds the peer a connect asked for under the given id, returning the verify answering it
+    fn accept_connect(
+        &mut self,
+        peer_id: PeerID,
+        addr: SocketAddr,
+        connect: &ConnectCommand,
+        validated: &ConnectCommand,
+    ) -> Result<(Peer, VerifyConnectCommand)> {
+        let (mut peer, sender) = self.new_peer_handle(peer_id, addr);
+        let mut peer_info = PeerInfo::new(peer_id, addr, validated.channel_count as usize, sender);
+        let verify = self.verify_connect(peer_info.incoming_peer_id, connect, validated)?;
+        peer_info.outgoing_peer_id = connect.outgoing_peer_id.into();
+        peer_info.connect_id = connect.connect_id;
+        peer_info.incoming_bandwidth = connect.incoming_bandwidth;
+        peer_info.outgoing_bandwidth = connect.outgoing_bandwidth;
+        peer_info.packet_throttle_interval = validated.packet_throttle_interval;
+        peer_info.packet_throttle_acceleration = validated.packet_throttle_acceleration;
+        peer_info.packet_throttle_deceleration = validated.packet_throttle_deceleration;
+        peer_info.connect_data = connect.data;
+        peer_info.update_window_size(self.config.outgoing_bandwidth.unwrap_or(0));
+        peer_info.mtu = validated.mtu;
+        peer_info.outgoing_session_id = verify.incoming_session_id.into();
+        peer_info.incoming_session_id = verify.outgoing_session_id.into();
+        self.recalculate_bandwidth_limits = true;
+        self.socket
+            .set_connect_id(peer_id, Some(connect.connect_id));
 
-        let mtu = connect.mtu;
-        let window_size = connect.window_size;
+        peer.connect_info = peer_info.connect_info();
+        peer.stats = peer_info.stats_sender.subscribe();
+        self.peers.insert(peer_id, peer_info);
 
-        // TODO Hande repeat connects
-        let peer_id = PeerID(self.next_peer);
-        self.next_peer += 1;
+        Ok((peer, verify))
+    }
 
-        let (to_cli_tx, to_cli_rx) = tokio::sync::mpsc::channel(100);
-        let peer = Peer {
-            address: addr,
-            id: peer_id,
-            out_channel: self.from_cli_tx.clone(),
-            in_channel: to_cli_rx,
-        };
+    /// Builds the verify answering a connect for the given peer id
+    fn verify_connect(
+        &self,
+        peer_id: PeerID,
+        connect: &ConnectCommand,
+        validated: &ConnectCommand,
+    ) -> Result<VerifyConnectCommand> {
+        let (last_incoming, last_outgoing) = self
+            .session_ids
+            .get(&peer_id)
+            .copied()
+            .unwrap_or((0xFF, 0xFF));
 
-        // Create all channels ahead of time
-        let channels = (0..channel_count as u16)
-            .map(|x| (x, Channel::default()))
-            .collect();
+        // Both sessions move past the ones last used, so stale datagrams are dropped
+        let incoming_session_id = next_session_id(connect.incoming_session_id, last_outgoing);
+        let outgoing_session_id = next_session_id(connect.outgoing_session_id, last_incoming);
 
-        let peer_info = self.peers.entry(peer_id).or_insert(PeerInfo {
-            outgoing_peer_id: connect.outgoing_peer_id.into(),
-            incoming_peer_id: peer_id,
-            connect_id: connect.connect_id,
-            outgoing_session_id: 0xFF,
-            incoming_session_id: 0xFF,
-            address: addr,
-            channels,
-            incoming_bandwidth: connect.incoming_bandwidth,
-            outgoing_bandwidth: connect.outgoing_bandwidth,
+        Ok(VerifyConnectCommand {
+            outgoing_peer_id: peer_id.into(),
+            incoming_session_id: incoming_session_id.try_into()?,
+            outgoing_session_id: outgoing_session_id.try_into()?,
+            mtu: validated.mtu,
+            window_size: validated.window_size,
+            channel_count: validated.channel_count,
+            incoming_bandwidth: self.config.incoming_bandwidth.unwrap_or(0),
+            outgoing_bandwidth: self.config.outgoing_bandwidth.unwrap_or(0),
+            // The remote checks these match what it sent, even if clamped for our own use
             packet_throttle_interval: connect.packet_throttle_interval,
             packet_throttle_acceleration: connect.packet_throttle_acceleration,
             packet_throttle_deceleration: connect.packet_throttle_deceleration,
-            _event_data: connect.data,
-            sender: to_cli_tx,
-            incoming_reliable_sequence_number: 0,
-            outgoing_reliable_sequence_number: 0,
-            _window_size: window_size,
-            _mtu: mtu,
-            last_msg_time: Instant::now(),
-            round_trip_time: Duration::from_millis(500),
-            round_trip_time_variance: Duration::ZERO,
-        });
+            connect_id: connect.connect_id,
+        })
+    }
 
-        // Handle incoming session id
-        let mut incoming_session_id = if connect.incoming_session_id == 0xFF {
-            peer_info.outgoing_peer_id
-        } else {
-            connect.incoming_session_id.into()
-        };
-        if incoming_session_id == peer_info.outgoing_peer_id {
-            incoming_session_id = ((incoming_session_id.0 + 1) & 3).into();
+    /// Checks a connect against the protocol limits, returning it with every field clamped
+    /// to what this host will use
+    fn validate_connect(
+        &self,
+        connect: &ConnectCommand,
+    ) -> std::result::Result<ConnectCommand, ConnectError> {
+        let channel_count = connect.channel_count as usize;
+        if !(PROTOCOL_MINIMUM_CHANNEL_COUNT..=PROTOCOL_MAXIMUM_CHANNEL_COUNT)
+            .contains(&channel_count)
+        {
+            return Err(ConnectError::ChannelCount(connect.channel_count));
+        }
+        if connect.outgoing_peer_id as usize >= PROTOCOL_MAXIMUM_PEER_ID {
+            return Err(ConnectError::PeerId(connect.outgoing_peer_id));
         }
-        peer_info.outgoing_session_id = incoming_session_id.into();
 
-        // Handle outgoing session id
-        let mut outgoing_session_id = if connect.outgoing_session_id == 0xFF {
-            peer_info.incoming_peer_id
-        } else {
-            connect.outgoing_session_id.into()
+        let channel_limit = self
+            .config
+            .channel_limit
+            .unwrap_or(PROTOCOL_MAXIMUM_CHANNEL_COUNT)
+            .clamp(
+                PROTOCOL_MINIMUM_CHANNEL_COUNT,
+                PROTOCOL_MAXIMUM_CHANNEL_COUNT,
+            );
+        let mtu = (connect.mtu as usize)
+            .clamp(PROTOCOL_MINIMUM_MTU, PROTOCOL_MAXIMUM_MTU)
+            .min(self.config.mtu);
+
+        // The window the remote may use when sending to us
+        let window_size = bandwidth_window_size(self.config.incoming_bandwidth.unwrap_or(0))
+            .min(connect.window_size)
+            .clamp(
+                PROTOCOL_MINIMUM_WINDOW_SIZE as u32,
+                PROTOCOL_MAXIMUM_WINDOW_SIZE as u32,
+            );
+
+        Ok(ConnectCommand {
+            channel_count: channel_count.min(channel_limit) as u32,
+            mtu: mtu as u32,
+            window_size,
+            packet_throttle_acceleration: connect
+                .packet_throttle_acceleration
+                .min(PEER_PACKET_THROTTLE_SCALE),
+            packet_throttle_deceleration: connect
+                .packet_throttle_deceleration
+                .min(PEER_PACKET_THROTTLE_SCALE),
+            ..connect.clone()
+        })
+    }
+
+    /// Handles an acknowledgement, returning the command it acknowledged if it was still unacked
+    fn handle_ack(
+        &mut self,
+        peer_id: PeerID,
+        channel: ChannelID,
+        ack: &AcknowledgeCommand,
+    ) -> Result<Option<ProtocolCommand>> {
+        let now = self.config.start_time.elapsed();
+        let Some(rtt) = ack.received_sent_time.elapsed(&now) else {
+            tracing::debug!("Ignoring acknowledgement sent ahead of now from peer {peer_id}");
+            return Ok(None);
         };
-        if outgoing_session_id == peer_info.incoming_peer_id {
-            outgoing_session_id = ((outgoing_session_id.0 + 1) & 3).into();
+        let rtt = rtt.max(Duration::from_millis(1));
+
+        let acked =
+            self.unack_packets
+                .remove(&(peer_id, channel, ack.received_reliable_sequence_number));
+        let peer = self.get_peer_mut(peer_id)?;
+        if let Some(acked) = &acked {
+            let length = acked.command.command.data_length() as u32;
+            peer.reliable_data_in_transit = peer.reliable_data_in_transit.saturating_sub(length);
         }
-        peer_info.incoming_session_id = outgoing_session_id.into();
 
-        let incoming_session_id = 0;
-        let outgoing_session_id = 0;
+        if peer.round_trip_time_sampled {
+            peer.update_throttle(rtt);
+        }
+        peer.update_round_trip_time(rtt);
+        peer.earliest_timeout = None;
+        peer.update_throttle_epoch(now);
+        peer.update_packet_loss(now);
+        Ok(acked.map(|acked| acked.command.command))
+    }
 
-        let verify = VerifyConnectCommand {
-            outgoing_peer_id: peer_info.incoming_peer_id.into(),
-            incoming_session_id: incoming_session_id.try_into()?,
-            outgoing_session_id: outgoing_session_id.try_into()?,
-            mtu,
-            window_size,
+    /// Connects to a remote host, returning the peer once the remote verifies the connection
+    pub async fn connect(
+        &mut self,
+        addr: SocketAddr,
+        channel_count: usize,
+        data: u32,
+    ) -> Result<Peer> {
+        let channel_count = channel_count.clamp(
+            PROTOCOL_MINIMUM_CHANNEL_COUNT,
+            PROTOCOL_MAXIMUM_CHANNEL_COUNT,
+        );
+
+        let peer_id = self.free_peer_id()?;
+
+        let (mut peer, sender) = self.new_peer_handle(peer_id, addr);
+        let mut peer_info = PeerInfo::new(peer_id, addr, channel_count, sender);
+        peer_info.state = PeerState::Connecting;
+        peer_info.connect_data = data;
+        peer_info.mtu = self.config.mtu.try_into()?;
+        peer_info.connect_id = self.random.read();
+        if let Some(&(incoming, outgoing)) = self.session_ids.get(&peer_id) {
+            peer_info.incoming_session_id = incoming;
+            peer_info.outgoing_session_id = outgoing;
+        }
+        peer_info.window_size = bandwidth_window_size(self.config.outgoing_bandwidth.unwrap_or(0));
+
+        let connect = ConnectCommand {
+            outgoing_peer_id: peer_id.into(),
+            incoming_session_id: peer_info.incoming_session_id.try_into()?,
+            outgoing_session_id: peer_info.outgoing_session_id.try_into()?,
+            mtu: peer_info.mtu,
+            window_size: peer_info.window_size,
             channel_count: channel_count.try_into()?,
             incoming_bandwidth: self.config.incoming_bandwidth.unwrap_or(0),
             outgoing_bandwidth: self.config.outgoing_bandwidth.unwrap_or(0),
@@ -201,51 +362,86 @@ impl Host {
             packet_throttle_acceleration: peer_info.packet_throttle_acceleration,
             packet_throttle_deceleration: peer_info.packet_throttle_deceleration,
             connect_id: peer_info.connect_id,
+            data,
         };
+        self.socket
+            .set_connect_id(peer_id, Some(connect.connect_id));
+        peer.stats = peer_info.stats_sender.subscribe();
+        self.peers.insert(peer_id, peer_info);
+
+        let info = self.new_command_info(peer_id, 0xFF, PacketFlags::reliable())?;
+        self.send(Command {
+            info,
+            command: connect.into(),
+        })
+        .await?;
 
-        Ok((peer, verify))
+        loop {
+            match self.peers.get(&peer_id) {
+                Some(info) if info.state == PeerState::Connected => {
+                    peer.connect_info = info.connect_info();
+                    return Ok(peer);
+                }
+                Some(info) if info.state == PeerState::Connecting => {}
+                _ => return Err(ENetError::ConnectFailed(addr)),
+            }
+
+            // Hold onto other events so they are still seen by the next poll
+            match self.service(self.config.poll_duration).await {
+                Ok(HostPollEvent::NoEvent) => {}
+                Ok(HostPollEvent::Disconnect(id, _)) if id == peer_id => {}
+                Ok(event) => self.pending_events.push_back(event),
+                Err(e) => tracing::warn!("Host err: {e}"),
+            }
+        }
     }
 
-    fn handle_ack(
+    fn handle_verify_connect(
         &mut self,
         peer_id: PeerID,
-        channel: ChannelID,
-        ack: &AcknowledgeCommand,
-    ) -> Result<()> {
-        self.unack_packets
-            .remove(&(peer_id, channel, ack.received_reliable_sequence_number));
-        let rtt = ack
-            .received_sent_time
-            .to_duration(&self.config.start_time.elapsed())
-            .ok_or(ENetError::InvalidPacket())?;
-
+        verify: &VerifyConnectCommand,
+    ) -> Result<HostPollEvent> {
         let peer = self.get_peer_mut(peer_id)?;
+        if peer.state != PeerState::Connecting {
+            // The remote did not get our ack and resent the verify
+            return Ok(HostPollEvent::NoEvent);
+        }
 
-        let diff = if rtt > peer.round_trip_time {
-            rtt - peer.round_trip_time
-        } else {
-            peer.round_trip_time - rtt
-        };
+        let channel_count: usize = verify.channel_count.try_into()?;
+        if !(PROTOCOL_MINIMUM_CHANNEL_COUNT..=PROTOCOL_MAXIMUM_CHANNEL_COUNT)
+            .contains(&channel_count)
+            || verify.packet_throttle_interval != peer.packet_throttle_interval
+            || verify.packet_throttle_acceleration != peer.packet_throttle_acceleration
+            || verify.packet_throttle_deceleration != peer.packet_throttle_deceleration
+            || verify.connect_id != peer.connect_id
+        {
+            tracing::debug!("Received invalid verify connect: {verify:?}");
+            self.remove_peer(peer_id);
+            self.socket.set_connect_id(peer_id, None);
+            self.unack_packets.retain(|k, _| k.0 != peer_id);
+            return Err(ENetError::InvalidPacket());
+        }
 
-        peer.round_trip_time_variance -= peer.round_trip_time_variance / 4;
+        peer.channels.retain(|id, _| (*id as usize) < channel_count);
+        peer.outgoing_peer_id = verify.outgoing_peer_id.into();
+        peer.incoming_session_id = verify.incoming_session_id.into();
+        peer.outgoing_session_id = verify.outgoing_session_id.into();
 
-        peer.round_trip_time = peer.round_trip_time.saturating_add(diff / 8);
-        peer.round_trip_time_variance += diff / 4;
-        Ok(())
-    }
+        let mtu = (verify.mtu as usize).clamp(PROTOCOL_MINIMUM_MTU, PROTOCOL_MAXIMUM_MTU);
+        peer.mtu = peer.mtu.min(mtu.try_into()?);
 
-    pub async fn connect(
-        &mut self,
-        _addr: SocketAddr,
-        _channel_count: usize,
-        _data: u32,
-    ) -> Result<PeerID> {
-        // TODO check channel count
-        todo!();
-        // let new_peer = PeerInfo::new(addr);
-        // let peer = PeerID(self.next_peer);
-        // self.next_peer += 1;
-        // self.peers.insert(peer, new_peer);
+        let window_size = (verify.window_size as usize)
+            .clamp(PROTOCOL_MINIMUM_WINDOW_SIZE, PROTOCOL_MAXIMUM_WINDOW_SIZE);
+        peer.window_size = peer.window_size.min(window_size.try_into()?);
+
+        peer.incoming_bandwidth = verify.incoming_bandwidth;
+        peer.outgoing_bandwidth = verify.outgoing_bandwidth;
+        peer.state = PeerState::Connected;
+        self.recalculate_bandwidth_limits = true;
+
+        // The verify acts as the acknowledgement of the connect
+        self.unack_packets.remove(&(peer_id, 0xFF, 1));
+        Ok(HostPollEvent::NoEvent)
     }
 
     pub async fn poll(&mut self) -> Result<HostPollEvent> {
@@ -260,6 +456,13 @@ impl Host {
     }
 
     pub async fn poll_for_event(&mut self, poll_time: Duration) -> Result<HostPollEvent> {
+        if let Some(event) = self.pending_events.pop_front() {
+            return Ok(event);
+        }
+        self.service(poll_time).await
+    }
+
+    async fn service(&mut self, poll_time: Duration) -> Result<HostPollEvent> {
         // Receive messages and pass them off
         // Send messages
         // Resend any messages that havent been resent again
@@ -268,13 +471,32 @@ impl Host {
 
         for disc_peer in timed_out {
             tracing::debug!("Disconnecting peer due to time out");
-            self.disconnect_peer(disc_peer).await?;
+            // A connect that never finished is reported by connect itself
+            let connecting = self
+                .peers
+                .get(&disc_peer)
+                .is_some_and(|p| p.state == PeerState::Connecting);
+            self.close_peer(disc_peer, DisconnectReason::Timeout)
+                .await?;
+            self.stats.timeouts += 1;
+            if !connecting {
+                self.pending_events.push_back(HostPollEvent::Disconnect(
+                    disc_peer,
+                    DisconnectReason::Timeout,
+                ));
+            }
         }
 
         self.send_pings().await?;
-        select! {
+        self.bandwidth_throttle().await?;
+        self.flush_outgoing().await;
+
+        let event = select! {
             incoming_command = self.socket.recv() => {
-                self.handle_incoming_command(&incoming_command?).await
+                let command = incoming_command?;
+                let event = self.handle_incoming_command(&command).await;
+                self.publish_stats(command.info.peer_id);
+                event
             }
             outgoing_event = self.receiver.recv() => {
                 match outgoing_event {
@@ -286,58 +508,113 @@ impl Host {
             _sleep = tokio::time::sleep(poll_time) => {
                 Ok(HostPollEvent::NoEvent)
             }
+        };
+
+        self.flush_outgoing().await;
+        event
+    }
+
+    /// Disconnects gracefully, keeping the peer until the remote acknowledges the disconnect
+    async fn disconnect_peer(&mut self, id: PeerID, data: u32) -> Result<()> {
+        let peer = self.get_peer_mut(id)?;
+        match peer.state {
+            PeerState::Disconnecting(_) => return Ok(()),
+            // Without a connection there is nobody to acknowledge the disconnect
+            PeerState::Connecting => return self.disconnect_peer_now(id, data).await,
+            PeerState::Connected | PeerState::DisconnectLater(_) => {}
         }
+        peer.state = PeerState::Disconnecting(data);
+
+        let info = self.new_command_info(id, 0xFF, PacketFlags::reliable())?;
+        self.send(Command {
+            info,
+            command: DisconnectCommand { data }.into(),
+        })
+        .await
     }
 
-    async fn disconnect_peer(&mut self, id: PeerID) -> Result<PeerInfo> {
-        tracing::debug!("Disconnecting peer {id}");
-        self.unack_packets.retain(|k, _| k.0 != id);
+    /// Sends a single unsequenced disconnect and forgets the peer without waiting on the remote
+    async fn disconnect_peer_now(&mut self, id: PeerID, data: u32) -> Result<()> {
+        let info = self.new_command_info(id, 0xFF, PacketFlags::unsequenced())?;
+        self.transmit(Command {
+            info,
+            command: DisconnectCommand { data }.into(),
+        })?;
+        self.close_peer(id, DisconnectReason::Local(data)).await
+    }
 
-        let info = self.new_command_info(id, 0xFF, PacketFlags::default())?;
-        tracing::trace!("Info output: {:?}", info);
+    /// Disconnects once every reliable command sent to the peer was acknowledged
+    async fn disconnect_peer_later(&mut self, id: PeerID, data: u32) -> Result<()> {
+        if self.has_unacked_commands(id)? {
+            self.get_peer_mut(id)?.state = PeerState::DisconnectLater(data);
+            return Ok(());
+        }
+        self.disconnect_peer(id, data).await
+    }
 
-        let send_result = self
-            .send(Command {
-                info,
-                command: DisconnectCommand { data: 0 }.into(),
-            })
-            .await;
+    /// Whether reliable commands to the peer are still waiting to be sent or acknowledged
+    fn has_unacked_commands(&self, id: PeerID) -> Result<bool> {
+        let peer = self.get_peer(id)?;
+        Ok(!peer.outgoing_reliable_commands.is_empty()
+            || self.unack_packets.keys().any(|k| k.0 == id))
+    }
 
-        tracing::debug!("Removed player");
-        let peer = self.peers.remove(&id);
-        send_result?;
+    /// Forgets a peer after sending what was already queued for it, and tells its handle why
+    async fn close_peer(&mut self, id: PeerID, reason: DisconnectReason) -> Result<()> {
+        tracing::debug!("Removing peer {id}");
+        // Queued commands are checksummed with the connect id, so they go out before it is dropped
+        self.flush_outgoing().await;
+
+        self.unack_packets.retain(|k, _| k.0 != id);
+        let peer = self.remove_peer(id);
+        self.socket.set_connect_id(id, None);
+        self.recalculate_bandwidth_limits = true;
         if let Some(peer) = peer {
             let _result = peer
                 .sender
                 .send(HostSendEvent {
-                    event: PeerRecvEvent::Disconnect,
+                    event: PeerRecvEvent::Disconnect(reason),
                     _channel_id: 0xFF,
                 })
                 .await;
-            return Ok(peer);
         }
-        Err(ENetError::InvalidPeerId(id))
+        Ok(())
     }
 
     async fn handle_outgoing_command(&mut self, event: HostRecvEvent) -> Result<HostPollEvent> {
         match &event.event {
-            crate::peer::PeerSendEvent::Broadcast(_) => {
+            PeerSendEvent::Broadcast(_) => {
                 let peers = self
                     .peers
-                    .keys()
-                    .filter(|x| **x != event.peer_id)
-                    .copied()
+                    .iter()
+                    .filter(|(id, p)| **id != event.peer_id && p.state == PeerState::Connected)
+                    .map(|(id, _)| *id)
                     .collect::<Vec<_>>();
                 for peer in peers {
                     let mut event = event.clone();
                     event.peer_id = peer;
-                    let command = event.to_command(self).await?;
-                    self.send(command).await?;
+                    for command in event.to_commands(self).await? {
+                        self.send(command).await?;
+                    }
                 }
             }
+            PeerSendEvent::Disconnect(data) => self.disconnect_peer(event.peer_id, *data).await?,
+            PeerSendEvent::DisconnectLater(data) => {
+                self.disconnect_peer_later(event.peer_id, *data).await?
+            }
+            PeerSendEvent::DisconnectNow(data) => {
+                self.disconnect_peer_now(event.peer_id, *data).await?
+            }
+            PeerSendEvent::Reset => {
+                self.outgoing_commands
+                    .retain(|c| c.info.internal_peer_id != event.peer_id);
+                self.close_peer(event.peer_id, DisconnectReason::Local(0))
+                    .await?
+            }
             _ => {
-                let command = event.to_command(self).await?;
-                self.send(command).await?;
+                for command in event.to_commands(self).await? {
+                    self.send(command).await?;
+                }
             }
         }
         Ok(HostPollEvent::NoEvent)
@@ -345,35 +622,103 @@ impl Host {
 
     async fn handle_incoming_command(&mut self, command: &Command) -> Result<HostPollEvent> {
         tracing::trace!("Handling incoming command: {command:?}");
-        self.preprocess_packet(command).await?;
+        if !self.in_current_session(command) {
+            tracing::debug!(
+                "Dropping command from an old session of peer {}",
+                command.info.peer_id
+            );
+            return Ok(HostPollEvent::NoEvent);
+        }
+        if self.config.connect_cookies
+            && !self.peers.contains_key(&command.info.peer_id)
+            && !matches!(command.command, ProtocolCommand::Connect(_))
+        {
+            return self.handle_cookie_command(command).await;
+        }
+        if !self.preprocess_packet(command).await? {
+            return Ok(HostPollEvent::NoEvent);
+        }
         tracing::trace!("Continuing packet");
 
         match &command.command {
             ProtocolCommand::Connect(c) => {
-                let (peer, verify_command) = self.handle_connect(command.info.addr, c)?;
-                let verify_command = Command {
-                    command: verify_command.into(),
-                    info: self.new_command_info(peer.id, 0xFF, PacketFlags::reliable())?,
-                };
-                self.send(verify_command).await?;
-                return Ok(HostPollEvent::Connect(peer));
-            }
-            ProtocolCommand::VerifyConnect(_) => todo!(),
-            ProtocolCommand::Disconnect(_) => {
+                let event = self.answer_connect(command.info.addr, c).await;
+                if let Err(ENetError::NoFreePeers | ENetError::InvalidConnect(_)) = event {
+                    self.stats.connects_refused += 1;
+                }
+                return event;
+            }
+            ProtocolCommand::VerifyConnect(v) => {
+                let event = self.handle_verify_connect(command.info.peer_id, v)?;
+                // Ack after verifying so the reply carries the remote's peer id
+                self.send_ack_packet(command).await?;
+                return Ok(event);
+            }
+            ProtocolCommand::Disconnect(d) => {
                 tracing::debug!("Disconnecting peer due to external request");
-                self.disconnect_peer(command.info.peer_id).await?;
-                return Ok(HostPollEvent::Disconnect(command.info.peer_id));
+                let reason = DisconnectReason::Remote(d.data);
+                self.close_peer(command.info.peer_id, reason).await?;
+                return Ok(HostPollEvent::Disconnect(command.info.peer_id, reason));
             }
             ProtocolCommand::BandwidthLimit(b) => {
+                let outgoing_bandwidth = self.config.outgoing_bandwidth.unwrap_or(0);
                 let peer = self.get_peer_mut(command.info.peer_id)?;
                 peer.incoming_bandwidth = b.incoming_bandwidth;
                 peer.outgoing_bandwidth = b.outgoing_bandwidth;
-                // TODO Handle window calculations
+                peer.update_window_size(outgoing_bandwidth);
+            }
+            ProtocolCommand::ThrottleConfigure(t) => {
+                let peer = self.get_peer_mut(command.info.peer_id)?;
+                peer.packet_throttle_interval = t.packet_throttle_interval;
+                peer.packet_throttle_acceleration = t
+                    .packet_throttle_acceleration
+                    .min(PEER_PACKET_THROTTLE_SCALE);
+                peer.packet_throttle_deceleration = t
+                    .packet_throttle_deceleration
+                    .min(PEER_PACKET_THROTTLE_SCALE);
+            }
+            ProtocolCommand::SendReliable(_r) if command.info.channel_id == 0xFF => {
+                self.forward_to_peer(command).await?
+            }
+            ProtocolCommand::SendReliable(_) | ProtocolCommand::SendFragment(_) => {
+                self.queue_reliable(command).await?
+            }
+            ProtocolCommand::SendUnreliable(r) => {
+                let peer = self.get_peer_mut(command.info.peer_id)?;
+                peer.get_mut_channel(command.info.channel_id.into())?
+                    .discard_stale_fragments(
+                        command.info.reliable_sequence_number,
+                        r.unreliable_sequence_number,
+                    );
+                self.forward_to_peer(command).await?
+            }
+            ProtocolCommand::SendUnsequenced(u) => {
+                let peer = self.get_peer_mut(command.info.peer_id)?;
+                if peer.accept_unsequenced(u.unsequenced_group) {
+                    self.forward_to_peer(command).await?
+                }
+            }
+            ProtocolCommand::SendUnreliableFragment(f) => {
+                self.handle_send_unreliable_fragment(command, f).await?
             }
-            ProtocolCommand::SendReliable(_r) => self.forward_to_peer(command).await?,
-            ProtocolCommand::SendUnreliable(_r) => self.forward_to_peer(command).await?,
             ProtocolCommand::Ack(r) => {
-                self.handle_ack(command.info.peer_id, command.info.channel_id.into(), r)?
+                let peer_id = command.info.peer_id;
+                let acked = self.handle_ack(peer_id, command.info.channel_id.into(), r)?;
+                self.send_queued_reliable(peer_id).await?;
+
+                match self.get_peer(peer_id)?.state {
+                    PeerState::Disconnecting(data)
+                        if matches!(acked, Some(ProtocolCommand::Disconnect(_))) =>
+                    {
+                        let reason = DisconnectReason::Local(data);
+                        self.close_peer(peer_id, reason).await?;
+                        return Ok(HostPollEvent::Disconnect(peer_id, reason));
+                    }
+                    PeerState::DisconnectLater(data) if !self.has_unacked_commands(peer_id)? => {
+                        self.disconnect_peer(peer_id, data).await?
+                    }
+                    _ => {}
+                }
             }
             ProtocolCommand::Ping(_) => {}
 
@@ -384,75 +729,143 @@ impl Host {
         Ok(HostPollEvent::NoEvent)
     }
 
-    async fn preprocess_packet(&mut self, command: &Command) -> Result<()> {
-        if let ProtocolCommand::Connect(_) = command.command {
-            return Ok(());
+    /// Answers a connect that is not a retransmission with a verify, unless the connect policy
+    /// rejects it
+    async fn answer_connect(
+        &mut self,
+        addr: SocketAddr,
+        connect: &ConnectCommand,
+    ) -> Result<HostPollEvent> {
+        if self.resend_verify(addr, connect.connect_id)?
+            || self.resend_cookie_verify(addr, connect.connect_id).await?
+        {
+            return Ok(HostPollEvent::NoEvent);
         }
+        let Some(connect) = self.apply_connect_policy(addr, connect).await? else {
+            return Ok(HostPollEvent::NoEvent);
+        };
+        if self.config.connect_cookies {
+            self.challenge_connect(addr, connect).await?;
+            return Ok(HostPollEvent::NoEvent);
+        }
+        let (peer, verify_command) = self.handle_connect(addr, &connect)?;
+        let verify_command = Command {
+            command: verify_command.into(),
+            info: self.new_command_info(peer.id, 0xFF, PacketFlags::reliable())?,
+        };
+        self.send(verify_command).await?;
+        self.stats.connects_accepted += 1;
+        Ok(HostPollEvent::Connect(peer))
+    }
 
+    /// Accounts for and acknowledges a command, returning whether it should be handled
+    async fn preprocess_packet(&mut self, command: &Command) -> Result<bool> {
+        match command.command {
+            ProtocolCommand::Connect(_) | ProtocolCommand::VerifyConnect(_) => return Ok(true),
+            _ => {}
+        }
+
+        let size = command_size(&command.command)? as u32;
         let peer = self.get_peer_mut(command.info.peer_id)?;
         peer.last_msg_time = Instant::now();
+        peer.incoming_data_total = peer.incoming_data_total.saturating_add(size);
+        peer.stats.bytes_received += size as u64;
+        peer.stats.packets_received += 1;
 
         if command.info.flags.reliable {
-            self.send_ack_packet(command).await?;
+            // Commands too far ahead are left unacknowledged so the remote resends them later
+            let ahead = match peer.get_channel(command.info.channel_id.into()) {
+                Ok(channel) => {
+                    channel.reliable_window(command.info.reliable_sequence_number)
+                        == ReliableWindow::Ahead
+                }
+                Err(_) => false,
+            };
+            if !ahead {
+                self.send_ack_packet(command).await?;
+            }
         }
 
-        'seq_number: {
-            let (current_seq, recv_seq) = match &command.command {
-                ProtocolCommand::SendReliable(_) if command.info.channel_id == 0xff => {
-                    let peer = self.get_peer_mut(command.info.peer_id)?;
-
-                    let sequence_num = &mut peer.incoming_reliable_sequence_number;
-                    let recv_seq = command.info.reliable_sequence_number;
-                    (sequence_num, recv_seq)
+        match &command.command {
+            // Data on the system channel has no channel to order it, so only repeats are dropped
+            ProtocolCommand::SendReliable(_) if command.info.channel_id == 0xFF => {
+                let peer = self.get_peer_mut(command.info.peer_id)?;
+                let seq = command.info.reliable_sequence_number;
+                if !sequence_before(peer.incoming_reliable_sequence_number, seq) {
+                    tracing::trace!("Dropping repeated reliable command {seq}");
+                    return Ok(false);
                 }
+                peer.incoming_reliable_sequence_number = seq;
+            }
+            ProtocolCommand::SendUnreliable(p) => {
+                let peer = self.get_peer_mut(command.info.peer_id)?;
+                let channel = peer.get_mut_channel(command.info.channel_id.into())?;
+                let seq = p.unreliable_sequence_number;
+                if !channel.accept_unreliable(command, seq) {
+                    tracing::trace!("Holding or dropping unreliable command {seq}");
+                    return Ok(false);
+                }
+            }
+            _ => {}
+        }
 
-                ProtocolCommand::SendReliable(_) => {
-                    let peer = self.get_peer_mut(command.info.peer_id)?;
-                    let channel = peer.get_mut_channel(command.info.channel_id.into())?;
-
-                    let sequence_num = &mut channel.incoming_reliable_sequence_number;
-                    let recv_seq = command.info.reliable_sequence_number;
+        Ok(true)
+    }
 
-                    (sequence_num, recv_seq)
-                }
+    /// Holds a reliable command until every command before it on the channel was delivered
+    async fn queue_reliable(&mut self, command: &Command) -> Result<()> {
+        let peer = self.get_peer_mut(command.info.peer_id)?;
+        let channel_id = command.info.channel_id.into();
+        let channel = peer.get_mut_channel(channel_id)?;
+
+        let seq = command.info.reliable_sequence_number;
+        if channel.reliable_window(seq) != ReliableWindow::Accept {
+            tracing::trace!(
+                "Dropping reliable command {seq}, expected after {}",
+                channel.incoming_reliable_sequence_number
+            );
+            return Ok(());
+        }
 
-                ProtocolCommand::SendUnreliable(p) => {
-                    let peer = self.get_peer_mut(command.info.peer_id)?;
-                    let channel = peer.get_mut_channel(command.info.channel_id.into())?;
+        channel
+            .incoming_reliable_commands
+            .insert(seq, command.clone());
+        self.dispatch_reliable(command.info.peer_id, channel_id)
+            .await
+    }
 
-                    let sequence_num = &mut channel.incoming_unreliable_sequence_number;
-                    let recv_seq = p.unreliable_sequence_number;
-                    (sequence_num, recv_seq)
-                }
-                _ => break 'seq_number,
+    async fn dispatch_reliable(&mut self, peer_id: PeerID, channel_id: ChannelID) -> Result<()> {
+        loop {
+            let channel = self.get_peer_mut(peer_id)?.get_mut_channel(channel_id)?;
+            let next_seq = channel.incoming_reliable_sequence_number.wrapping_add(1);
+            let Some(command) = channel.incoming_reliable_commands.remove(&next_seq) else {
+                return Ok(());
             };
 
-            let next_seq = current_seq.wrapping_add(1);
+            channel.incoming_reliable_sequence_number = next_seq;
+            // Unreliable sequences restart after every reliable command on a channel
+            channel.incoming_unreliable_sequence_number = 0;
 
-            if recv_seq != next_seq {
-                tracing::debug!(
-                    "Invalid sequence number: Received {} != Expected {}",
-                    recv_seq,
-                    next_seq
-                );
-
-                // TODO Handle the invalid seq numbers similar to original enet windowing
-                return Ok(());
+            match &command.command {
+                ProtocolCommand::SendFragment(f) => self.handle_send_fragment(&command, f).await?,
+                _ => self.forward_to_peer(&command).await?,
             }
 
-            // TODO Determine if peer seq num can be merged
-            *current_seq = next_seq;
-        };
-
-        Ok(())
+            let held = self
+                .get_peer_mut(peer_id)?
+                .get_mut_channel(channel_id)?
+                .release_unreliable();
+            for command in held {
+                self.forward_to_peer(&command).await?;
+            }
+        }
     }
 
     async fn forward_to_peer(&mut self, command: &Command) -> Result<()> {
-        let peer = self.get_peer(command.info.peer_id)?;
-
         let data = match &command.command {
             ProtocolCommand::SendReliable(r) => r.data.clone(),
             ProtocolCommand::SendUnreliable(r) => r.data.clone(),
+            ProtocolCommand::SendUnsequenced(r) => r.data.clone(),
             _ => unreachable!("Invalid packet type forwarded to peer"),
         };
 
@@ -462,11 +875,127 @@ impl Host {
             flags: command.info.flags.clone(),
         };
 
+        self.deliver_packet(command.info.peer_id, packet).await
+    }
+
+    async fn handle_send_fragment(
+        &mut self,
+        command: &Command,
+        fragment: &SendFragmentCommand,
+    ) -> Result<()> {
+        let peer = self.get_peer_mut(command.info.peer_id)?;
+        let channel_id = command.info.channel_id.into();
+        let channel = peer.get_mut_channel(channel_id)?;
+
+        let start = fragment.start_sequence_number;
+        let buffer = match channel.incoming_fragments.entry(start) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(FragmentedPacket::new(
+                fragment.fragment_count,
+                fragment.total_length,
+            )?),
+        };
+
+        let complete = buffer.insert(
+            fragment.fragment_count,
+            fragment.fragment_number,
+            fragment.total_length,
+            fragment.fragment_offset,
+            &fragment.data,
+        )?;
+        if !complete {
+            return Ok(());
+        }
+
+        let Some(buffer) = channel.incoming_fragments.remove(&start) else {
+            return Ok(());
+        };
+
+        let packet = Packet {
+            data: buffer.data,
+            channel: channel_id,
+            flags: PacketFlags::reliable(),
+        };
+        self.deliver_packet(command.info.peer_id, packet).await
+    }
+
+    async fn handle_send_unreliable_fragment(
+        &mut self,
+        command: &Command,
+        fragment: &SendUnreliableFragmentCommand,
+    ) -> Result<()> {
+        let peer = self.get_peer_mut(command.info.peer_id)?;
+        let channel_id = command.info.channel_id.into();
+        let channel = peer.get_mut_channel(channel_id)?;
+
+        let reliable = command.info.reliable_sequence_number;
+        let start = fragment.start_sequence_number;
+
+        // Fragments of a group that was already superseded can never be delivered
+        if (reliable == channel.incoming_reliable_sequence_number
+            && !sequence_before(channel.incoming_unreliable_sequence_number, start))
+            || channel.has_newer_fragments(reliable, start)
+        {
+            tracing::trace!("Dropping stale unreliable fragment {start}");
+            return Ok(());
+        }
+        channel.discard_stale_fragments(reliable, start);
+
+        let buffer = match channel
+            .incoming_unreliable_fragments
+            .entry((reliable, start))
+        {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(FragmentedPacket::new(
+                fragment.fragment_count,
+                fragment.total_length,
+            )?),
+        };
+
+        let complete = buffer.insert(
+            fragment.fragment_count,
+            fragment.fragment_number,
+            fragment.total_length,
+            fragment.fragment_offset,
+            &fragment.data,
+        )?;
+        if !complete {
+            return Ok(());
+        }
+
+        let Some(buffer) = channel
+            .incoming_unreliable_fragments
+            .remove(&(reliable, start))
+        else {
+            return Ok(());
+        };
+
+        // The group used one unreliable sequence number per fragment
+        if reliable == channel.incoming_reliable_sequence_number {
+            channel.incoming_unreliable_sequence_number =
+                start.wrapping_add((fragment.fragment_count - 1) as u16);
+        }
+
+        let packet = Packet {
+            data: buffer.data,
+            channel: channel_id,
+            flags: PacketFlags {
+                unreliable_fragment: true,
+                ..Default::default()
+            },
+        };
+        self.deliver_packet(command.info.peer_id, packet).await
+    }
+
+    async fn deliver_packet(&mut self, peer_id: PeerID, packet: Packet) -> Result<()> {
+        let peer = self.get_peer(peer_id)?;
+        let channel_id = packet.channel;
+
         let output = peer
             .sender
             .send(HostSendEvent {
                 event: PeerRecvEvent::Recv(packet),
-                _channel_id: command.info.channel_id.into(),
+                _channel_id: channel_id,
             })
             .await;
 
@@ -475,6 +1004,74 @@ impl Host {
         Ok(())
     }
 
+    /// Splits a packet too large for the peer's mtu into fragments
+    pub(crate) fn fragment_packet(
+        &mut self,
+        peer_id: PeerID,
+        packet: &Packet,
+    ) -> Result<Vec<Command>> {
+        let checksum = self.config.checksum;
+        let peer = self.get_peer_mut(peer_id)?;
+        let fragment_length = peer.fragment_length(checksum);
+        let channel = peer.get_channel(packet.channel)?;
+
+        let fragment_count = packet.data.len().div_ceil(fragment_length);
+        if fragment_count > PROTOCOL_MAXIMUM_FRAGMENT_COUNT {
+            return Err(ENetError::PacketTooLarge(packet.data.len()));
+        }
+
+        // Unreliable fragments fall back to reliable ones when the sequence is exhausted
+        let unreliable =
+            !packet.flags.reliable && channel.outgoing_unreliable_sequence_number < 0xFFFF;
+        let (start_sequence_number, flags) = if unreliable {
+            let flags = PacketFlags {
+                unreliable_fragment: true,
+                unsequenced: false,
+                ..packet.flags.clone()
+            };
+            (channel.outgoing_unreliable_sequence_number + 1, flags)
+        } else {
+            let start = channel.outgoing_reliable_sequence_number.wrapping_add(1);
+            (start, PacketFlags::reliable())
+        };
+
+        let fragment_count = fragment_count.try_into()?;
+        let total_length = packet.data.len().try_into()?;
+
+        let mut commands = Vec::new();
+        for (fragment_number, data) in packet.data.chunks(fragment_length).enumerate() {
+            let fragment_number = fragment_number.try_into()?;
+            let fragment_offset = fragment_number * fragment_length as u32;
+            let data = data.to_vec();
+
+            let command = if unreliable {
+                SendUnreliableFragmentCommand {
+                    start_sequence_number,
+                    fragment_count,
+                    fragment_number,
+                    total_length,
+                    fragment_offset,
+                    data,
+                }
+                .into()
+            } else {
+                SendFragmentCommand {
+                    start_sequence_number,
+                    fragment_count,
+                    fragment_number,
+                    total_length,
+                    fragment_offset,
+                    data,
+                }
+                .into()
+            };
+
+            let info = self.new_command_info(peer_id, packet.channel, flags.clone())?;
+            commands.push(Command { info, command });
+        }
+        Ok(commands)
+    }
+
     fn new_command_info(
         &mut self,
         peer_id: PeerID,
@@ -483,10 +1080,16 @@ impl Host {
     ) -> Result<CommandInfo> {
         let peer = self.get_peer_mut(peer_id)?;
 
+        // Commands on the system channel take its next sequence number even when unsequenced,
+        // as in ENet
         let reliable_sequence_number = if channel_id == 0xFF {
             peer.outgoing_reliable_sequence_number =
                 peer.outgoing_reliable_sequence_number.wrapping_add(1);
             peer.outgoing_reliable_sequence_number
+        } else if flags.unsequenced && !flags.reliable {
+            // Unsequenced commands are ordered by their group instead
+            peer.outgoing_unsequenced_group = peer.outgoing_unsequenced_group.wrapping_add(1);
+            0
         } else {
             let channel = peer.get_mut_channel(channel_id)?;
 
@@ -498,7 +1101,6 @@ impl Host {
             } else {
                 channel.outgoing_unreliable_sequence_number =
                     channel.outgoing_unreliable_sequence_number.wrapping_add(1);
-                channel.outgoing_reliable_sequence_number = 1;
                 channel.outgoing_reliable_sequence_number
             }
         };
@@ -512,8 +1114,8 @@ impl Host {
             internal_peer_id: peer_id,
             channel_id,
             reliable_sequence_number,
+            session_id: peer.header_session_id(),
             sent_time: self.config.start_time.elapsed(),
-            session_id: 0,
         };
         Ok(info)
     }
@@ -534,17 +1136,15 @@ impl Host {
             peer_id: peer.outgoing_peer_id.into(),
             internal_peer_id: command.info.peer_id,
             channel_id: command.info.channel_id,
-            session_id: 0,
+            session_id: peer.header_session_id(),
             reliable_sequence_number: command.info.reliable_sequence_number,
             sent_time: self.config.start_time.elapsed(),
         };
 
-        self.socket
-            .send(&Command {
-                command: ack_command,
-                info: ack_info,
-            })
-            .await
+        self.transmit(Command {
+            command: ack_command,
+            info: ack_info,
+        })
     }
 
     async fn resend_missing_packets(&mut self) -> Result<Vec<PeerID>> {
@@ -555,19 +1155,44 @@ impl Host {
         //         .map(|(k, v)| (k, v.peer_id))
         //         .collect::<Vec<_>>()
         // );
-        let resend_packets = self.unack_packets.iter_mut().filter(|x| {
-            self.config.start_time.elapsed() - x.1.last_sent > self.config.packet_timeout
-        });
-
+        let now = self.config.start_time.elapsed();
         let mut disconnected = Vec::new();
-        for (_k, p) in resend_packets {
-            if p.retries >= self.config.retry_count {
-                disconnected.push(p.peer_id);
+        for p in self.unack_packets.values_mut() {
+            if now.saturating_sub(p.last_sent) < p.round_trip_timeout {
                 continue;
             }
-            self.socket.send(&p.command).await?;
-            p.retries += 1;
-            p.last_sent = self.config.start_time.elapsed();
+            let Some(peer) = self.peers.get_mut(&p.peer_id) else {
+                continue;
+            };
+
+            // A port of enet_protocol_check_timeouts, the peer times out once commands went
+            // unacknowledged for long enough, measured from the oldest one that timed out
+            let earliest_timeout = peer
+                .earliest_timeout
+                .map_or(p.last_sent, |t| t.min(p.last_sent));
+            peer.earliest_timeout = Some(earliest_timeout);
+            let waited = now.saturating_sub(earliest_timeout);
+            if waited >= self.config.timeout_maximum
+                || (p.round_trip_timeout >= p.round_trip_timeout_limit
+                    && waited >= self.config.timeout_minimum)
+            {
+                if !disconnected.contains(&p.peer_id) {
+                    disconnected.push(p.peer_id);
+                }
+                continue;
+            }
+
+            // Acknowledgements echo the sent time, which has to be this resend's for the round
+            // trip sample to leave out the time spent waiting on the timeout
+            p.command.info.sent_time = now;
+            self.outgoing_commands.push(p.command.clone());
+            peer.count_sent(command_size(&p.command.command)?, true);
+            peer.stats.retransmits += 1;
+            self.stats.retransmits += 1;
+            peer.packets_lost = peer.packets_lost.saturating_add(1);
+            peer.update_packet_loss(now);
+            p.round_trip_timeout *= 2;
+            p.last_sent = now;
         }
         Ok(disconnected)
     }
@@ -576,6 +1201,7 @@ impl Host {
         let update_peers: Vec<_> = self
             .peers
             .iter()
+            .filter(|(_, v)| v.state == PeerState::Connected)
             .filter(|(_, v)| v.last_msg_time.elapsed() > self.config.ping_interval)
             .map(|(k, _)| *k)
             .collect();
@@ -598,32 +1224,454 @@ impl Host {
         Ok(())
     }
 
+    /// Sets the policy deciding which incoming connects are accepted
+    pub fn set_connect_policy(&mut self, policy: impl ConnectPolicy + 'static) {
+        self.connect_policy = Some(Box::new(policy));
+    }
+
+    /// Asks the connect policy about an incoming connect, returning the connect to accept or
+    /// None if it was rejected
+    async fn apply_connect_policy(
+        &mut self,
+        addr: SocketAddr,
+        connect: &ConnectCommand,
+    ) -> Result<Option<ConnectCommand>> {
+        let validated = self.validate_connect(connect)?;
+        let Some(policy) = self.connect_policy.as_mut() else {
+            return Ok(Some(connect.clone()));
+        };
+
+        let request = ConnectRequest {
+            address: addr,
+            data: connect.data,
+            channel_count: validated.channel_count as usize,
+        };
+        match policy.check(&request).await {
+            ConnectDecision::Accept => Ok(Some(connect.clone())),
+            ConnectDecision::AcceptChannels(channel_count) => {
+                let channel_count =
+                    channel_count.clamp(PROTOCOL_MINIMUM_CHANNEL_COUNT, request.channel_count);
+                Ok(Some(ConnectCommand {
+                    channel_count: channel_count as u32,
+                    ..connect.clone()
+                }))
+            }
+            ConnectDecision::Reject(data) => {
+                tracing::debug!("Rejected connect from {addr}");
+                self.stats.connects_refused += 1;
+                self.reject_connect(addr, connect, data).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Answers a connect with an unsequenced disconnect, without adding a peer for it
+    async fn reject_connect(
+        &mut self,
+        addr: SocketAddr,
+        connect: &ConnectCommand,
+        data: u32,
+    ) -> Result<()> {
+        // The id meaning no peer is never handed out, so its connect id only seeds this checksum
+        let no_peer = PeerID(PROTOCOL_MAXIMUM_PEER_ID as u16);
+        self.socket
+            .set_connect_id(no_peer, Some(connect.connect_id));
+        let info = CommandInfo {
+            addr,
+            flags: PacketFlags::unsequenced(),
+            internal_peer_id: no_peer,
+            peer_id: connect.outgoing_peer_id.into(),
+            channel_id: 0xFF,
+            session_id: 0,
+            reliable_sequence_number: 0,
+            sent_time: self.config.start_time.elapsed(),
+        };
+        let sent = self
+            .socket
+            .send(&Command {
+                info,
+                command: DisconnectCommand { data }.into(),
+            })
+            .await;
+        self.socket.set_connect_id(no_peer, None);
+        sent
+    }
+
+    /// Changes the host's bandwidth limits in bytes per second and sends them to every
+    /// connected peer
+    pub async fn set_bandwidth_limit(
+        &mut self,
+        incoming_bandwidth: Option<u32>,
+        outgoing_bandwidth: Option<u32>,
+    ) -> Result<()> {
+        self.config.incoming_bandwidth = incoming_bandwidth;
+        self.config.outgoing_bandwidth = outgoing_bandwidth;
+
+        let outgoing_bandwidth = outgoing_bandwidth.unwrap_or(0);
+        for peer in self.peers.values_mut() {
+            peer.update_window_size(outgoing_bandwidth);
+        }
+        self.recalculate_bandwidth_limits = false;
+        self.send_bandwidth_limits(self.config.start_time.elapsed())
+            .await?;
+        self.flush_outgoing().await;
+        Ok(())
+    }
+
+    /// Shares the host's outgoing bandwidth across its peers by limiting their throttles,
+    /// a port of enet_host_bandwidth_throttle
+    async fn bandwidth_throttle(&mut self) -> Result<()> {
+        let now = self.config.start_time.elapsed();
+        let elapsed = now.saturating_sub(self.bandwidth_throttle_epoch);
+        if elapsed < Duration::from_millis(HOST_BANDWIDTH_THROTTLE_INTERVAL) {
+            return Ok(());
+        }
+        self.bandwidth_throttle_epoch = now;
+
+        let connected: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, v)| v.state == PeerState::Connected)
+            .map(|(k, _)| *k)
+            .collect();
+        if connected.is_empty() {
+            return Ok(());
+        }
+
+        let elapsed = elapsed.as_millis() as u64;
+        let scale = PEER_PACKET_THROTTLE_SCALE as u64;
+        let throttle_for = |data_total: u64, bandwidth: u64| {
+            if data_total <= bandwidth {
+                scale
+            } else {
+                bandwidth * scale / data_total
+            }
+        };
+
+        let (mut data_total, mut bandwidth) = match self.config.outgoing_bandwidth {
+            None | Some(0) => (u64::MAX, u64::MAX),
+            Some(outgoing) => {
+                let data_total = connected
+                    .iter()
+                    .map(|id| self.peers[id].outgoing_data_total as u64)
+                    .sum();
+                (data_total, outgoing as u64 * elapsed / 1000)
+            }
+        };
+
+        // Peers that can take less than their share get limited to what they can take
+        let mut peers_remaining = connected.len();
+        let mut needs_adjustment = connected
+            .iter()
+            .any(|id| self.peers[id].incoming_bandwidth != 0);
+        while peers_remaining > 0 && needs_adjustment {
+            needs_adjustment = false;
+            let throttle = throttle_for(data_total, bandwidth);
+
+            for id in &connected {
+                let peer = self.get_peer_mut(*id)?;
+                if peer.incoming_bandwidth == 0
+                    || peer.outgoing_bandwidth_throttle_epoch == Some(now)
+                {
+                    continue;
+                }
+
+                let peer_bandwidth = peer.incoming_bandwidth as u64 * elapsed / 1000;
+                let outgoing_data_total = peer.outgoing_data_total as u64;
+                if throttle * outgoing_data_total / scale <= peer_bandwidth {
+                    continue;
+                }
+
+                let limit = (peer_bandwidth * scale / outgoing_data_total).max(1);
+                peer.limit_throttle(limit as u32);
+                peer.outgoing_bandwidth_throttle_epoch = Some(now);
+
+                needs_adjustment = true;
+                peers_remaining -= 1;
+                bandwidth = bandwidth.saturating_sub(peer_bandwidth);
+                data_total = data_total.saturating_sub(peer_bandwidth);
+            }
+        }
+
+        // Everyone else splits what is left evenly
+        if peers_remaining > 0 {
+            let throttle = throttle_for(data_total, bandwidth) as u32;
+            for id in &connected {
+                let peer = self.get_peer_mut(*id)?;
+                if peer.outgoing_bandwidth_throttle_epoch != Some(now) {
+                    peer.limit_throttle(throttle);
+                }
+            }
+        }
+
+        if self.recalculate_bandwidth_limits {
+            self.recalculate_bandwidth_limits = false;
+            self.send_bandwidth_limits(now).await?;
+        }
+        Ok(())
+    }
+
+    /// Splits the host's incoming bandwidth across connected peers and tells each one
+    /// how much it may send
+    async fn send_bandwidth_limits(&mut self, now: Duration) -> Result<()> {
+        let connected: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, v)| v.state == PeerState::Connected)
+            .map(|(k, _)| *k)
+            .collect();
+
+        let mut peers_remaining = connected.len() as u32;
+        let mut bandwidth = self.config.incoming_bandwidth.unwrap_or(0);
+        let mut bandwidth_limit = 0;
+        let mut needs_adjustment = bandwidth != 0;
+
+        // Peers sending less than an even share keep their own rate
+        while peers_remaining > 0 && needs_adjustment {
+            needs_adjustment = false;
+            bandwidth_limit = bandwidth / peers_remaining;
+
+            for id in &connected {
+                let peer = self.get_peer_mut(*id)?;
+                if peer.incoming_bandwidth_throttle_epoch == Some(now)
+                    || (peer.outgoing_bandwidth > 0 && peer.outgoing_bandwidth >= bandwidth_limit)
+                {
+                    continue;
+                }
+
+                peer.incoming_bandwidth_throttle_epoch = Some(now);
+                needs_adjustment = true;
+                peers_remaining -= 1;
+                bandwidth = bandwidth.saturating_sub(peer.outgoing_bandwidth);
+            }
+        }
+
+        for id in connected {
+            let peer = self.get_peer(id)?;
+            let incoming_bandwidth = if peer.incoming_bandwidth_throttle_epoch == Some(now) {
+                peer.outgoing_bandwidth
+            } else {
+                bandwidth_limit
+            };
+
+            let command = BandwidthLimitCommand {
+                incoming_bandwidth,
+                outgoing_bandwidth: self.config.outgoing_bandwidth.unwrap_or(0),
+            };
+            let info = self.new_command_info(id, 0xFF, PacketFlags::reliable())?;
+            self.send(Command {
+                info,
+                command: command.into(),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
     pub async fn broadcast(&mut self, event: HostRecvEvent) -> Result<()> {
         let peers: Vec<_> = self.peers.keys().map(Clone::clone).collect();
         for _peer in peers {
             let event = event.clone();
             self.handle_outgoing_command(event).await?;
         }
+        self.flush_outgoing().await;
         Ok(())
     }
 
+    /// Sends a command, holding reliable data back while the peer's window is full
     pub(crate) async fn send(&mut self, command: Command) -> Result<()> {
-        self.socket.send(&command).await?;
+        let length = command.command.data_length() as u32;
+        if command.info.flags.reliable && length > 0 {
+            if let Some(peer) = self.peers.get_mut(&command.info.internal_peer_id) {
+                if !peer.outgoing_reliable_commands.is_empty() || !peer.window_has_room(length) {
+                    peer.outgoing_reliable_commands.push_back(command);
+                    return Ok(());
+                }
+                peer.reliable_data_in_transit += length;
+            }
+        }
+        self.transmit(command)
+    }
+
+    /// Sends reliable commands that were held back until the window has no room left
+    async fn send_queued_reliable(&mut self, peer_id: PeerID) -> Result<()> {
+        loop {
+            let Some(peer) = self.peers.get_mut(&peer_id) else {
+                return Ok(());
+            };
+            let Some(command) = peer.outgoing_reliable_commands.front() else {
+                return Ok(());
+            };
+            let length = command.command.data_length() as u32;
+            if !peer.window_has_room(length) {
+                return Ok(());
+            }
+
+            if let Some(mut command) = peer.outgoing_reliable_commands.pop_front() {
+                peer.reliable_data_in_transit += length;
+                command.info.sent_time = self.config.start_time.elapsed();
+                self.transmit(command)?;
+            }
+        }
+    }
+
+    /// Queues a command to go out with the next datagram to its peer
+    fn transmit(&mut self, command: Command) -> Result<()> {
+        let Some(peer) = self.peers.get_mut(&command.info.internal_peer_id) else {
+            self.outgoing_commands.push(command);
+            return Ok(());
+        };
+        peer.count_sent(command_size(&command.command)?, command.info.flags.reliable);
 
         if command.info.flags.reliable {
+            let unack = UnAckPacket::new(
+                command.clone(),
+                peer.retransmit_timeout(),
+                self.config.timeout_limit,
+            );
             self.unack_packets.insert(
                 (
                     command.info.internal_peer_id,
                     command.info.channel_id.into(),
                     command.info.reliable_sequence_number,
                 ),
-                UnAckPacket::new(command),
+                unack,
             );
         }
 
+        self.outgoing_commands.push(command);
         Ok(())
     }
 
+    /// Packs queued commands into as few datagrams per peer as the peer's mtu allows, a peer
+    /// that cannot be sent to does not hold up the others
+    async fn flush_outgoing(&mut self) {
+        let mut outgoing = std::mem::take(&mut self.outgoing_commands);
+        let now = self.config.start_time.elapsed();
+        while let Some(first) = outgoing.first() {
+            let peer_id = first.info.internal_peer_id;
+            let (mut commands, rest): (Vec<_>, Vec<_>) = outgoing
+                .into_iter()
+                .partition(|c| c.info.internal_peer_id == peer_id);
+            outgoing = rest;
+
+            // A datagram carries a single sent time, which the remote echoes when acknowledging
+            // any of its commands, so every command is stamped with the time it actually left
+            for command in &mut commands {
+                command.info.sent_time = now;
+                if !command.info.flags.reliable {
+                    continue;
+                }
+                let key = (
+                    peer_id,
+                    command.info.channel_id.into(),
+                    command.info.reliable_sequence_number,
+                );
+                if let Some(unack) = self.unack_packets.get_mut(&key) {
+                    unack.command.info.sent_time = now;
+                    unack.last_sent = now;
+                }
+            }
+
+            // Commands for peers removed since queueing are packed to the smallest mtu
+            let mtu = self
+                .peers
+                .get(&peer_id)
+                .map_or(PROTOCOL_MINIMUM_MTU, |p| p.mtu as usize);
+            if let Err(e) = self.socket.send_packed(&commands, mtu).await {
+                tracing::warn!("Failed to send to peer {peer_id}: {e}");
+            }
+            self.publish_stats(peer_id);
+        }
+    }
+
+    /// Shares a peer's latest stats with its handles
+    fn publish_stats(&self, peer_id: PeerID) {
+        if let Some(peer) = self.peers.get(&peer_id) {
+            peer.publish_stats();
+        }
+    }
+
+    fn new_peer_handle(&self, id: PeerID, address: SocketAddr) -> (Peer, Sender<HostSendEvent>) {
+        let (to_cli_tx, to_cli_rx) = tokio::sync::mpsc::channel(100);
+        let peer = Peer {
+            address,
+            id,
+            connect_info: Default::default(),
+            // Replaced by the peer info's receiver once the peer is added
+            stats: tokio::sync::watch::channel(Default::default()).1,
+            out_channel: self.from_cli_tx.clone(),
+            in_channel: to_cli_rx,
+        };
+        (peer, to_cli_tx)
+    }
+
+    /// Sends the verify again if the connect is a retransmission of one already accepted,
+    /// returning whether it was
+    fn resend_verify(&mut self, addr: SocketAddr, connect_id: u32) -> Result<bool> {
+        let Some(peer_id) = self
+            .peers
+            .iter()
+            .find(|(_, p)| p.address == addr && p.connect_id == connect_id)
+            .map(|(id, _)| *id)
+        else {
+            return Ok(false);
+        };
+
+        // Once acknowledged the verify is gone, and the connect was only delayed
+        let now = self.config.start_time.elapsed();
+        let verify = self.unack_packets.values_mut().find(|p| {
+            p.peer_id == peer_id && matches!(p.command.command, ProtocolCommand::VerifyConnect(_))
+        });
+        if let Some(verify) = verify {
+            tracing::debug!("Resending verify connect to peer {peer_id}");
+            // Sent again as the same unacknowledged command, so its timeout restarts rather
+            // than having it resent and counted as lost right away
+            verify.command.info.sent_time = now;
+            verify.last_sent = now;
+            let command = verify.command.clone();
+            if let Some(peer) = self.peers.get_mut(&peer_id) {
+                peer.count_sent(command_size(&command.command)?, true);
+            }
+            self.outgoing_commands.push(command);
+        }
+        Ok(true)
+    }
+
+    /// Finds the lowest peer id not in use, the maximum peer id is never given out as it
+    /// means no peer on the wire
+    fn free_peer_id(&self) -> Result<PeerID> {
+        let peer_count = self.config.peer_count.min(PROTOCOL_MAXIMUM_PEER_ID);
+        (0..peer_count as u16)
+            .map(PeerID)
+            .find(|id| !self.peers.contains_key(id))
+            .ok_or(ENetError::NoFreePeers)
+    }
+
+    /// Removes a peer, remembering its sessions for the next peer given the same id
+    fn remove_peer(&mut self, peer_id: PeerID) -> Option<PeerInfo> {
+        let peer = self.peers.remove(&peer_id)?;
+        self.session_ids.insert(
+            peer_id,
+            (peer.incoming_session_id, peer.outgoing_session_id),
+        );
+        Some(peer)
+    }
+
+    /// Whether a command carries the session its peer is currently on
+    fn in_current_session(&self, command: &Command) -> bool {
+        if let ProtocolCommand::Connect(_) = command.command {
+            return true;
+        }
+        match self.peers.get(&command.info.peer_id) {
+            // Until the remote assigns a peer id it cannot know the session either
+            Some(peer) if (peer.outgoing_peer_id.0 as usize) < PROTOCOL_MAXIMUM_PEER_ID => {
+                command.info.session_id == peer.incoming_session_id
+            }
+            _ => true,
+        }
+    }
+
     pub(crate) fn get_peer_mut(&mut self, peer_id: PeerID) -> Result<&mut PeerInfo> {
         self.peers
             .get_mut(&peer_id)
@@ -636,7 +1684,41 @@ impl Host {
             .ok_or(ENetError::InvalidPeerId(peer_id))
     }
 
+    /// A snapshot of a connected peer's stats
+    pub fn peer_stats(&self, peer_id: PeerID) -> Result<PeerStats> {
+        Ok(self.get_peer(peer_id)?.stats())
+    }
+
+    /// The host's traffic and connection counters
+    pub fn stats(&self) -> HostStats {
+        HostStats {
+            socket: self.socket.stats(),
+            ..self.stats
+        }
+    }
+
+    /// Starts counting from zero again
+    pub fn reset_stats(&mut self) {
+        self.stats = HostStats::default();
+        self.socket.reset_stats();
+    }
+
     pub fn get_bind_address(&self) -> SocketAddr {
         self.bound_socket_addr
     }
 }
+
+/// Picks the session id after the one a peer id last used, unless the remote asked for one
+pub(crate) fn next_session_id(requested: u8, current: u16) -> u16 {
+    let session_id = if requested == 0xFF {
+        current
+    } else {
+        requested.into()
+    };
+    let session_id = (session_id + 1) & 3;
+    if session_id == current {
+        (session_id + 1) & 3
+    } else {
+        session_id
+    }
+}
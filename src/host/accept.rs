@@ -0,0 +1,39 @@
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+
+/// What a host knows about an incoming connect when deciding whether to accept it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectRequest {
+    pub address: SocketAddr,
+    /// The data sent along with the connect
+    pub data: u32,
+    /// The channels the remote asked for, already limited to the host's channel limit
+    pub channel_count: usize,
+}
+
+/// How a host answers an incoming connect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectDecision {
+    Accept,
+    /// Accept, but with at most the given number of channels
+    AcceptChannels(usize),
+    /// Refuse the connection, disconnecting the remote with the given data
+    Reject(u32),
+}
+
+/// Decides which incoming connects a host accepts
+#[async_trait]
+pub trait ConnectPolicy: Send {
+    async fn check(&mut self, request: &ConnectRequest) -> ConnectDecision;
+}
+
+#[async_trait]
+impl<F> ConnectPolicy for F
+where
+    F: FnMut(&ConnectRequest) -> ConnectDecision + Send,
+{
+    async fn check(&mut self, request: &ConnectRequest) -> ConnectDecision {
+        self(request)
+    }
+}
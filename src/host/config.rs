@@ -1,6 +1,12 @@
 use std::time::{Duration, Instant};
 
-use crate::error::Result;
+use crate::{
+    consts::{
+        HOST_DEFAULT_MTU, PEER_TIMEOUT_LIMIT, PEER_TIMEOUT_MAXIMUM, PEER_TIMEOUT_MINIMUM,
+        PROTOCOL_MAXIMUM_PEER_ID,
+    },
+    error::{ENetError, Result},
+};
 
 #[derive(Debug)]
 pub struct HostConfig {
@@ -9,14 +15,34 @@ pub struct HostConfig {
     pub incoming_bandwidth: Option<u32>,
     pub outgoing_bandwidth: Option<u32>,
     pub start_time: Instant,
-    pub retry_count: usize,
-    pub packet_timeout: Duration,
+    /// Multiple of a command's first retransmit timeout it may back off to before its peer
+    /// can time out
+    pub timeout_limit: u32,
+    /// How long a peer must go unacknowledged before timing out at the timeout limit
+    pub timeout_minimum: Duration,
+    /// How long a peer can go unacknowledged before always timing out
+    pub timeout_maximum: Duration,
     pub poll_duration: Duration,
     pub ping_interval: Duration,
+    /// Largest datagram sent to any peer, clamped to the protocol limits
+    pub mtu: usize,
+    /// Compress datagrams with ENet's range coder, both ends need it enabled
+    pub range_coder: bool,
+    /// Add ENet's CRC32 checksum to datagrams, both ends need it enabled
+    pub checksum: bool,
+    /// Only add a peer once the remote acknowledges a verify carrying a cookie, so connects
+    /// from spoofed addresses never get a peer or retransmitted verifies
+    pub connect_cookies: bool,
 }
 
 impl HostConfig {
     pub fn new(peer_count: usize) -> Result<Self> {
+        if peer_count > PROTOCOL_MAXIMUM_PEER_ID {
+            return Err(ENetError::BadConfig(format!(
+                "peer count {peer_count} is over the maximum of {PROTOCOL_MAXIMUM_PEER_ID}"
+            )));
+        }
+
         Ok(HostConfig {
             peer_count,
             poll_duration: Duration::from_secs(1),
@@ -24,9 +50,14 @@ impl HostConfig {
             incoming_bandwidth: None,
             outgoing_bandwidth: None,
             start_time: Instant::now(),
-            packet_timeout: Duration::from_secs(1),
-            retry_count: 5,
+            timeout_limit: PEER_TIMEOUT_LIMIT,
+            timeout_minimum: Duration::from_millis(PEER_TIMEOUT_MINIMUM),
+            timeout_maximum: Duration::from_millis(PEER_TIMEOUT_MAXIMUM),
             ping_interval: Duration::from_millis(500),
+            mtu: HOST_DEFAULT_MTU,
+            range_coder: false,
+            checksum: false,
+            connect_cookies: false,
         })
     }
 }
@@ -0,0 +1,196 @@
+//! Connect cookies, which keep a host from adding peers for connects whose sender never
+//! received the verify, such as ones from spoofed addresses
+
+use std::{hash::BuildHasher, net::SocketAddr, time::Duration};
+
+use super::{hostevents::HostPollEvent, Host};
+use crate::{
+    consts::{HOST_CONNECT_COOKIE_INTERVAL, HOST_CONNECT_COOKIE_SLOTS, PROTOCOL_MAXIMUM_PEER_ID},
+    error::{ENetError, Result},
+    net::socket::Socket,
+    peer::PeerID,
+    protocol::{Command, CommandInfo, ConnectCommand, PacketFlags, ProtocolCommand},
+};
+
+/// A connect answered with a cookie, which takes nothing but its slot until the remote
+/// acknowledges the verify
+#[derive(Debug)]
+pub(crate) struct CookieConnect {
+    address: SocketAddr,
+    /// The connect as allowed by the connect policy
+    connect: ConnectCommand,
+    /// The peer id the verify gave out, only taken once the cookie comes back
+    peer_id: PeerID,
+    /// The cookie interval the verify was last sent in
+    interval: u64,
+}
+
+impl Host {
+    /// Answers a connect with a single verify carrying a cookie in place of its reliable
+    /// sequence number and sent time, which the remote echoes back when acknowledging it
+    pub(crate) async fn challenge_connect(
+        &mut self,
+        addr: SocketAddr,
+        connect: ConnectCommand,
+    ) -> Result<()> {
+        // Checked now so invalid connects never take a slot
+        self.validate_connect(&connect)?;
+        if self.cookie_connects.is_empty() {
+            self.cookie_connects
+                .resize_with(HOST_CONNECT_COOKIE_SLOTS, || None);
+        }
+
+        let interval = self.cookie_interval();
+        let cookie = self.connect_cookie(addr, &connect, interval);
+        let peer_id = self.cookie_peer_id(cookie)?;
+        // A later connect hashing to the same slot takes it over, so connects are never refused
+        // for lack of room
+        let slot = self.cookie_slot(addr);
+        self.cookie_connects[slot] = Some(CookieConnect {
+            address: addr,
+            connect,
+            peer_id,
+            interval,
+        });
+        self.send_cookie_verify(slot).await
+    }
+
+    /// Sends the verify again if the connect is a retransmission of one still waiting on its
+    /// cookie, returning whether it was
+    pub(crate) async fn resend_cookie_verify(
+        &mut self,
+        addr: SocketAddr,
+        connect_id: u32,
+    ) -> Result<bool> {
+        let slot = self.cookie_slot(addr);
+        let interval = self.cookie_interval();
+        match self.cookie_connects.get_mut(slot) {
+            Some(Some(c)) if c.address == addr && c.connect.connect_id == connect_id => {
+                // Keeps the cookie an acknowledgement may already carry, unless it went stale
+                if interval > c.interval + 1 {
+                    c.interval = interval;
+                }
+            }
+            _ => return Ok(false),
+        }
+        self.send_cookie_verify(slot).await?;
+        Ok(true)
+    }
+
+    /// Handles a command sent to a peer id the host has not added, adding the peer once the
+    /// remote acknowledges its verify with the right cookie
+    pub(crate) async fn handle_cookie_command(
+        &mut self,
+        command: &Command,
+    ) -> Result<HostPollEvent> {
+        let addr = command.info.addr;
+        let slot = self.cookie_slot(addr);
+        let interval = self.cookie_interval();
+        let Some(Some(pending)) = self.cookie_connects.get(slot) else {
+            return Ok(HostPollEvent::NoEvent);
+        };
+        // Stale entries are left for a later connect to take over
+        if pending.address != addr
+            || pending.peer_id != command.info.peer_id
+            || interval > pending.interval + 1
+        {
+            return Ok(HostPollEvent::NoEvent);
+        }
+
+        match &command.command {
+            ProtocolCommand::Ack(ack) if command.info.channel_id == 0xFF => {
+                let echoed = (u32::from(ack.received_reliable_sequence_number) << 16)
+                    | u32::from(u16::from(ack.received_sent_time.clone()));
+                if echoed != self.connect_cookie(addr, &pending.connect, pending.interval) {
+                    tracing::debug!("Dropping acknowledgement with a bad cookie from {addr}");
+                    return Ok(HostPollEvent::NoEvent);
+                }
+
+                let Some(pending) = self.cookie_connects[slot].take() else {
+                    return Ok(HostPollEvent::NoEvent);
+                };
+                if self.peers.contains_key(&pending.peer_id) {
+                    tracing::debug!("Refusing connect from {addr}, its peer id was taken");
+                    self.stats.connects_refused += 1;
+                    return Ok(HostPollEvent::NoEvent);
+                }
+                let validated = self.validate_connect(&pending.connect)?;
+                let (peer, _) =
+                    self.accept_connect(pending.peer_id, addr, &pending.connect, &validated)?;
+                // The verify already went out with the cookie as its reliable sequence number
+                self.get_peer_mut(pending.peer_id)?
+                    .outgoing_reliable_sequence_number = ack.received_reliable_sequence_number;
+                self.stats.connects_accepted += 1;
+                Ok(HostPollEvent::Connect(peer))
+            }
+            // The remote considers itself connected, so its acknowledgement was lost
+            _ => {
+                self.send_cookie_verify(slot).await?;
+                Ok(HostPollEvent::NoEvent)
+            }
+        }
+    }
+
+    /// Sends the verify for a connect waiting on its cookie, which is never retransmitted on
+    /// its own
+    async fn send_cookie_verify(&mut self, slot: usize) -> Result<()> {
+        let Some(Some(pending)) = self.cookie_connects.get(slot) else {
+            return Ok(());
+        };
+        let validated = self.validate_connect(&pending.connect)?;
+        let verify = self.verify_connect(pending.peer_id, &pending.connect, &validated)?;
+        let cookie = self.connect_cookie(pending.address, &pending.connect, pending.interval);
+        // Checksums are seeded with the connect id, from the verify on, and the peer id is free
+        // until the cookie comes back, so nothing else relies on its connect id
+        self.socket
+            .set_connect_id(pending.peer_id, Some(pending.connect.connect_id));
+
+        let info = CommandInfo {
+            addr: pending.address,
+            flags: PacketFlags::reliable(),
+            internal_peer_id: pending.peer_id,
+            peer_id: pending.connect.outgoing_peer_id.into(),
+            channel_id: 0xFF,
+            session_id: (verify.incoming_session_id & 3).into(),
+            reliable_sequence_number: (cookie >> 16) as u16,
+            sent_time: Duration::from_millis((cookie & 0xFFFF).into()),
+        };
+        self.socket
+            .send(&Command {
+                info,
+                command: verify.into(),
+            })
+            .await
+    }
+
+    /// A keyed hash of the connect and the interval it was answered in
+    fn connect_cookie(&self, addr: SocketAddr, connect: &ConnectCommand, interval: u64) -> u32 {
+        self.cookie_key
+            .hash_one((addr, connect.connect_id, connect.outgoing_peer_id, interval)) as u32
+    }
+
+    /// The interval cookies are currently made in, each stays valid until the end of the next
+    fn cookie_interval(&self) -> u64 {
+        (self.config.start_time.elapsed().as_millis() / HOST_CONNECT_COOKIE_INTERVAL as u128) as u64
+    }
+
+    /// The slot a remote's connect waits in, keyed so spoofed addresses cannot aim at one
+    fn cookie_slot(&self, addr: SocketAddr) -> usize {
+        self.cookie_key.hash_one(addr) as usize % HOST_CONNECT_COOKIE_SLOTS
+    }
+
+    /// A free peer id to hand out in a verify, starting from one picked by the cookie so
+    /// connects waiting at the same time are unlikely to be given the same id
+    fn cookie_peer_id(&self, cookie: u32) -> Result<PeerID> {
+        let peer_count = self.config.peer_count.min(PROTOCOL_MAXIMUM_PEER_ID);
+        if peer_count == 0 {
+            return Err(ENetError::NoFreePeers);
+        }
+        let start = cookie as usize % peer_count;
+        (start..peer_count)
+            .chain(0..start)
+            .map(|id| PeerID(id as u16))
+            .find(|id| !self.peers.contains_key(id))
+            .ok_or(ENetError::NoFreePeers)
+    }
+}
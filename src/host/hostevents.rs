@@ -1,10 +1,10 @@
 use crate::{
     channel::ChannelID,
     error::{ENetError, Result},
-    peer::{Peer, PeerID, PeerRecvEvent, PeerSendEvent},
+    peer::{DisconnectReason, Peer, PeerID, PeerRecvEvent, PeerSendEvent, PeerState},
     protocol::{
-        Command, DisconnectCommand, PacketFlags, PingCommand, ProtocolCommand, SendReliableCommand,
-        SendUnreliableCommand,
+        Command, PacketFlags, PingCommand, ProtocolCommand, SendReliableCommand,
+        SendUnreliableCommand, SendUnsequencedCommand,
     },
 };
 
@@ -14,7 +14,8 @@ use super::Host;
 pub enum HostPollEvent {
     NoEvent,
     Connect(Peer),
-    Disconnect(PeerID),
+    /// A peer disconnected
+    Disconnect(PeerID, DisconnectReason),
 }
 
 #[derive(Debug)]
@@ -31,11 +32,31 @@ pub struct HostRecvEvent {
 }
 
 impl HostRecvEvent {
+    /// Converts the event into commands, fragmenting packets that exceed the peer's mtu
+    pub async fn to_commands(&self, host: &mut Host) -> Result<Vec<Command>> {
+        if let PeerSendEvent::Send(p) = &self.event {
+            let checksum = host.config.checksum;
+            let peer = host.get_peer_mut(self.peer_id)?;
+            if peer.state != PeerState::Connected {
+                tracing::trace!("Dropped packet to disconnecting peer {}", self.peer_id);
+                return Ok(Vec::new());
+            }
+            if !p.flags.reliable && peer.throttle_drop() {
+                tracing::trace!("Throttled unreliable packet to peer {}", self.peer_id);
+                return Ok(Vec::new());
+            }
+
+            if p.data.len() > peer.fragment_length(checksum) {
+                return host.fragment_packet(self.peer_id, p);
+            }
+        }
+
+        Ok(vec![self.to_command(host).await?])
+    }
+
     pub async fn to_command(&self, host: &mut Host) -> Result<Command> {
         let peer = host.get_peer_mut(self.peer_id)?;
 
-        let channel = peer.get_channel(self.channel_id)?;
-
         let (command, flags) = match &&self.event {
             PeerSendEvent::Send(p) if p.flags.reliable => (
                 ProtocolCommand::SendReliable(SendReliableCommand {
@@ -44,28 +65,52 @@ impl HostRecvEvent {
                 }),
                 p.flags.clone(),
             ),
-            PeerSendEvent::Send(p) => (
-                ProtocolCommand::SendUnreliable(SendUnreliableCommand {
-                    unreliable_sequence_number: (channel.outgoing_unreliable_sequence_number + 1),
-                    // data_length: p.data.len().try_into()?,
+            PeerSendEvent::Send(p) if p.flags.unsequenced => (
+                ProtocolCommand::SendUnsequenced(SendUnsequencedCommand {
+                    unsequenced_group: peer.outgoing_unsequenced_group.wrapping_add(1),
                     data: p.data.clone(),
                 }),
                 p.flags.clone(),
             ),
+            PeerSendEvent::Send(p) => {
+                let channel = peer.get_channel(self.channel_id)?;
+                (
+                    ProtocolCommand::SendUnreliable(SendUnreliableCommand {
+                        unreliable_sequence_number: (channel.outgoing_unreliable_sequence_number
+                            + 1),
+                        // data_length: p.data.len().try_into()?,
+                        data: p.data.clone(),
+                    }),
+                    p.flags.clone(),
+                )
+            }
             PeerSendEvent::Ping => (
                 ProtocolCommand::Ping(PingCommand {}),
                 PacketFlags::reliable(),
             ),
-            PeerSendEvent::Disconnect => (
-                ProtocolCommand::Disconnect(DisconnectCommand { data: 0 }),
-                PacketFlags::reliable(),
-            ),
+            PeerSendEvent::ThrottleConfigure(t) => {
+                peer.packet_throttle_interval = t.packet_throttle_interval;
+                peer.packet_throttle_acceleration = t.packet_throttle_acceleration;
+                peer.packet_throttle_deceleration = t.packet_throttle_deceleration;
+                (
+                    ProtocolCommand::ThrottleConfigure(t.clone()),
+                    PacketFlags::reliable(),
+                )
+            }
             PeerSendEvent::Broadcast(_) => {
                 // TODO Handle broadcast - avoid recursion
                 // host.broadcast(self.clone()).await?;
                 // return Ok(None);
                 return Err(ENetError::Other("Broadcast gave to to_command".to_string()));
             }
+            PeerSendEvent::Disconnect(_)
+            | PeerSendEvent::DisconnectLater(_)
+            | PeerSendEvent::DisconnectNow(_)
+            | PeerSendEvent::Reset => {
+                return Err(ENetError::Other(
+                    "Disconnect gave to to_command".to_string(),
+                ));
+            }
         };
 
         let info = host.new_command_info(self.peer_id, self.channel_id, flags)?;
@@ -0,0 +1,16 @@
+use crate::net::stats::SocketStats;
+
+/// Traffic and connection counters of a host since it was created or its stats were reset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HostStats {
+    /// Datagrams and commands as counted by the socket
+    pub socket: SocketStats,
+    /// Reliable commands resent after their acknowledgement timed out
+    pub retransmits: u64,
+    /// Peers disconnected for going unacknowledged too long
+    pub timeouts: u64,
+    pub connects_accepted: u64,
+    /// Connects rejected by the connect policy, invalid, or arriving while every peer slot
+    /// is taken
+    pub connects_refused: u64,
+}
@@ -1,4 +1,5 @@
 pub mod channel;
+pub mod consts;
 pub mod error;
 pub mod host;
 pub mod net;
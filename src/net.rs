@@ -1,7 +1,10 @@
+pub mod checksum;
+pub mod compress;
 pub mod deserializer;
 pub mod serializer;
 pub mod sizer;
 pub mod socket;
+pub mod stats;
 pub mod time;
 
 #[cfg(test)]
@@ -0,0 +1,572 @@
+//! An adaptive order-2 PPM range coder, a bit-exact port of ENet's `compress.c`
+
+const RANGE_CODER_TOP: u32 = 1 << 24;
+const RANGE_CODER_BOTTOM: u32 = 1 << 16;
+
+const CONTEXT_SYMBOL_DELTA: u16 = 3;
+const CONTEXT_SYMBOL_MINIMUM: u16 = 1;
+const CONTEXT_ESCAPE_MINIMUM: u16 = 1;
+
+const SUBCONTEXT_ORDER: usize = 2;
+const SUBCONTEXT_SYMBOL_DELTA: u16 = 2;
+const SUBCONTEXT_ESCAPE_DELTA: u16 = 5;
+
+/// Only enough symbols for reasonable mtus
+const SYMBOL_CAPACITY: usize = 4096;
+
+/// Compresses the commands of a datagram, everything after the protocol header
+pub trait Compressor: std::fmt::Debug + Send {
+    /// Returns None if the output would exceed `limit` bytes
+    fn compress(&mut self, input: &[u8], limit: usize) -> Option<Vec<u8>>;
+    /// Returns None if the input is invalid or the output would exceed `limit` bytes
+    fn decompress(&mut self, input: &[u8], limit: usize) -> Option<Vec<u8>>;
+}
+
+/// A node in a binary tree of symbols, which also acts as the context for the symbols after it
+#[derive(Debug, Default, Clone, Copy)]
+struct Symbol {
+    value: u8,
+    count: u8,
+    under: u16,
+    /// Offsets from this symbol to its children
+    left: u16,
+    right: u16,
+
+    /// Offset to the root of this context's tree
+    symbols: u16,
+    escapes: u16,
+    total: u16,
+    /// Index of the context one order lower
+    parent: u16,
+}
+
+/// The range coder ENet uses for `enet_host_compress_with_range_coder`
+#[derive(Debug)]
+pub struct RangeCoder {
+    symbols: Vec<Symbol>,
+    next_symbol: usize,
+}
+
+/// Where the next symbol index gets written, either the predicted context or a symbol's parent
+#[derive(Clone, Copy)]
+enum ParentSlot {
+    Predicted,
+    Symbol(usize),
+}
+
+impl Default for RangeCoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RangeCoder {
+    pub fn new() -> Self {
+        Self {
+            symbols: vec![Symbol::default(); SYMBOL_CAPACITY],
+            next_symbol: 0,
+        }
+    }
+
+    fn create_symbol(&mut self, value: u8, count: u16) -> usize {
+        let index = self.next_symbol;
+        self.next_symbol += 1;
+        self.symbols[index] = Symbol {
+            value,
+            count: count as u8,
+            under: count,
+            ..Default::default()
+        };
+        index
+    }
+
+    fn create_context(&mut self, escapes: u16, minimum: u16) -> usize {
+        let index = self.create_symbol(0, 0);
+        let context = &mut self.symbols[index];
+        context.escapes = escapes;
+        context.total = escapes.wrapping_add(256 * minimum);
+        context.symbols = 0;
+        index
+    }
+
+    /// Starts over with an empty model once the symbols run out
+    fn free_symbols(&mut self, root: &mut usize, predicted: &mut u16, order: &mut usize) {
+        if self.next_symbol >= SYMBOL_CAPACITY - SUBCONTEXT_ORDER {
+            self.next_symbol = 0;
+            *root = self.create_context(CONTEXT_ESCAPE_MINIMUM, CONTEXT_SYMBOL_MINIMUM);
+            *predicted = 0;
+            *order = 0;
+        }
+    }
+
+    fn set_parent(&mut self, slot: ParentSlot, predicted: &mut u16, symbol: usize) {
+        match slot {
+            ParentSlot::Predicted => *predicted = symbol as u16,
+            ParentSlot::Symbol(index) => self.symbols[index].parent = symbol as u16,
+        }
+    }
+
+    fn rescale_symbol(&mut self, mut index: usize) -> u16 {
+        let mut total: u16 = 0;
+        loop {
+            let symbol = &mut self.symbols[index];
+            symbol.count -= symbol.count >> 1;
+            symbol.under = symbol.count.into();
+            let left = symbol.left;
+            if left != 0 {
+                let under = self.rescale_symbol(index + left as usize);
+                self.symbols[index].under = self.symbols[index].under.wrapping_add(under);
+            }
+            total = total.wrapping_add(self.symbols[index].under);
+
+            let right = self.symbols[index].right;
+            if right == 0 {
+                return total;
+            }
+            index += right as usize;
+        }
+    }
+
+    fn rescale_context(&mut self, context: usize, minimum: u16) {
+        let symbols = self.symbols[context].symbols;
+        let total = if symbols != 0 {
+            self.rescale_symbol(context + symbols as usize)
+        } else {
+            0
+        };
+
+        let context = &mut self.symbols[context];
+        context.escapes -= context.escapes >> 1;
+        context.total = total
+            .wrapping_add(context.escapes)
+            .wrapping_add(256 * minimum);
+    }
+
+    /// Finds or inserts `value` in a context, returning the symbol with its under and count
+    fn encode_context(
+        &mut self,
+        context: usize,
+        value: u8,
+        update: u16,
+        minimum: u16,
+    ) -> (usize, u16, u16) {
+        let mut under = value as u16 * minimum;
+        let mut count = minimum;
+
+        if self.symbols[context].symbols == 0 {
+            let symbol = self.create_symbol(value, update);
+            self.symbols[context].symbols = (symbol - context) as u16;
+            return (symbol, under, count);
+        }
+
+        let mut node = context + self.symbols[context].symbols as usize;
+        loop {
+            let current = self.symbols[node];
+            if value < current.value {
+                self.symbols[node].under = current.under.wrapping_add(update);
+                if current.left != 0 {
+                    node += current.left as usize;
+                    continue;
+                }
+                let symbol = self.create_symbol(value, update);
+                self.symbols[node].left = (symbol - node) as u16;
+                return (symbol, under, count);
+            } else if value > current.value {
+                under = under.wrapping_add(current.under);
+                if current.right != 0 {
+                    node += current.right as usize;
+                    continue;
+                }
+                let symbol = self.create_symbol(value, update);
+                self.symbols[node].right = (symbol - node) as u16;
+                return (symbol, under, count);
+            } else {
+                count = count.wrapping_add(current.count.into());
+                under = under.wrapping_add(current.under.wrapping_sub(current.count.into()));
+                self.symbols[node].under = current.under.wrapping_add(update);
+                self.symbols[node].count = current.count.wrapping_add(update as u8);
+                return (node, under, count);
+            }
+        }
+    }
+
+    /// Finds the symbol a code falls on, returning the symbol, its value, under and count.
+    /// Subcontexts can't create symbols, so `create` is only set for the root.
+    fn decode_context(
+        &mut self,
+        context: usize,
+        code: u16,
+        update: u16,
+        minimum: u16,
+        create: bool,
+    ) -> Option<(usize, u8, u16, u16)> {
+        let mut under: u16 = 0;
+        let count = minimum;
+
+        if self.symbols[context].symbols == 0 {
+            if !create {
+                return None;
+            }
+            let value = (code / minimum) as u8;
+            let under = code - code % minimum;
+            let symbol = self.create_symbol(value, update);
+            self.symbols[context].symbols = (symbol - context) as u16;
+            return Some((symbol, value, under, count));
+        }
+
+        let mut node = context + self.symbols[context].symbols as usize;
+        loop {
+            let current = self.symbols[node];
+            let after = under
+                .wrapping_add(current.under)
+                .wrapping_add((current.value as u16 + 1).wrapping_mul(minimum));
+            let before = current.count as u16 + minimum;
+
+            if code >= after {
+                under = under.wrapping_add(current.under);
+                if current.right != 0 {
+                    node += current.right as usize;
+                    continue;
+                }
+                if !create {
+                    return None;
+                }
+                let value =
+                    (current.value as i32 + 1 + (code - after) as i32 / minimum as i32) as u8;
+                let under = code - (code - after) % minimum;
+                let symbol = self.create_symbol(value, update);
+                self.symbols[node].right = (symbol - node) as u16;
+                return Some((symbol, value, under, count));
+            } else if (code as i32) < after as i32 - before as i32 {
+                self.symbols[node].under = current.under.wrapping_add(update);
+                if current.left != 0 {
+                    node += current.left as usize;
+                    continue;
+                }
+                if !create {
+                    return None;
+                }
+                let distance = after as i32 - before as i32 - code as i32 - 1;
+                let value = (current.value as i32 - 1 - distance / minimum as i32) as u8;
+                let under = (code as i32 - distance % minimum as i32) as u16;
+                let symbol = self.create_symbol(value, update);
+                self.symbols[node].left = (symbol - node) as u16;
+                return Some((symbol, value, under, count));
+            } else {
+                self.symbols[node].under = current.under.wrapping_add(update);
+                self.symbols[node].count = current.count.wrapping_add(update as u8);
+                let count = count.wrapping_add(current.count.into());
+                return Some((node, current.value, after.wrapping_sub(before), count));
+            }
+        }
+    }
+}
+
+impl Compressor for RangeCoder {
+    fn compress(&mut self, input: &[u8], limit: usize) -> Option<Vec<u8>> {
+        if input.is_empty() {
+            return None;
+        }
+
+        let mut encoder = Encoder {
+            low: 0,
+            range: !0,
+            output: Vec::with_capacity(limit),
+            limit,
+        };
+        self.next_symbol = 0;
+        let mut root = self.create_context(CONTEXT_ESCAPE_MINIMUM, CONTEXT_SYMBOL_MINIMUM);
+        let mut predicted: u16 = 0;
+        let mut order = 0;
+
+        for &value in input {
+            let mut parent = ParentSlot::Predicted;
+            let mut subcontext = predicted as usize;
+            let mut encoded = false;
+
+            while subcontext != root {
+                let (symbol, under, count) =
+                    self.encode_context(subcontext, value, SUBCONTEXT_SYMBOL_DELTA, 0);
+                self.set_parent(parent, &mut predicted, symbol);
+                parent = ParentSlot::Symbol(symbol);
+
+                let context = self.symbols[subcontext];
+                if count > 0 {
+                    encoder.encode(
+                        context.escapes as u32 + under as u32,
+                        count.into(),
+                        context.total.into(),
+                    )?;
+                } else {
+                    if context.escapes > 0 && context.escapes < context.total {
+                        encoder.encode(0, context.escapes.into(), context.total.into())?;
+                    }
+                    let context = &mut self.symbols[subcontext];
+                    context.escapes = context.escapes.wrapping_add(SUBCONTEXT_ESCAPE_DELTA);
+                    context.total = context.total.wrapping_add(SUBCONTEXT_ESCAPE_DELTA);
+                }
+
+                let context = &mut self.symbols[subcontext];
+                context.total = context.total.wrapping_add(SUBCONTEXT_SYMBOL_DELTA);
+                if count > 0xFF - 2 * SUBCONTEXT_SYMBOL_DELTA
+                    || context.total as u32 > RANGE_CODER_BOTTOM - 0x100
+                {
+                    self.rescale_context(subcontext, 0);
+                }
+                if count > 0 {
+                    encoded = true;
+                    break;
+                }
+                subcontext = self.symbols[subcontext].parent as usize;
+            }
+
+            if !encoded {
+                let (symbol, under, count) =
+                    self.encode_context(root, value, CONTEXT_SYMBOL_DELTA, CONTEXT_SYMBOL_MINIMUM);
+                self.set_parent(parent, &mut predicted, symbol);
+
+                let context = self.symbols[root];
+                encoder.encode(
+                    context.escapes as u32 + under as u32,
+                    count.into(),
+                    context.total.into(),
+                )?;
+
+                let context = &mut self.symbols[root];
+                context.total = context.total.wrapping_add(CONTEXT_SYMBOL_DELTA);
+                if count > 0xFF - 2 * CONTEXT_SYMBOL_DELTA + CONTEXT_SYMBOL_MINIMUM
+                    || context.total as u32 > RANGE_CODER_BOTTOM - 0x100
+                {
+                    self.rescale_context(root, CONTEXT_SYMBOL_MINIMUM);
+                }
+            }
+
+            if order >= SUBCONTEXT_ORDER {
+                predicted = self.symbols[predicted as usize].parent;
+            } else {
+                order += 1;
+            }
+            self.free_symbols(&mut root, &mut predicted, &mut order);
+        }
+
+        encoder.flush()?;
+        Some(encoder.output)
+    }
+
+    fn decompress(&mut self, input: &[u8], limit: usize) -> Option<Vec<u8>> {
+        if input.is_empty() {
+            return None;
+        }
+
+        let mut decoder = Decoder {
+            low: 0,
+            code: 0,
+            range: !0,
+            input,
+        };
+        self.next_symbol = 0;
+        let mut root = self.create_context(CONTEXT_ESCAPE_MINIMUM, CONTEXT_SYMBOL_MINIMUM);
+        let mut predicted: u16 = 0;
+        let mut order = 0;
+        let mut output = Vec::with_capacity(limit);
+
+        decoder.seed();
+
+        loop {
+            let mut parent = ParentSlot::Predicted;
+            let mut subcontext = predicted as usize;
+            let mut decoded = None;
+
+            while subcontext != root {
+                let context = self.symbols[subcontext];
+                if context.escapes == 0 || context.escapes >= context.total {
+                    subcontext = context.parent as usize;
+                    continue;
+                }
+
+                let code = decoder.read(context.total)?;
+                if code < context.escapes {
+                    decoder.decode(0, context.escapes.into());
+                    subcontext = context.parent as usize;
+                    continue;
+                }
+
+                let (symbol, value, under, count) = self.decode_context(
+                    subcontext,
+                    code - context.escapes,
+                    SUBCONTEXT_SYMBOL_DELTA,
+                    0,
+                    false,
+                )?;
+                decoder.decode(context.escapes as u32 + under as u32, count.into());
+
+                let context = &mut self.symbols[subcontext];
+                context.total = context.total.wrapping_add(SUBCONTEXT_SYMBOL_DELTA);
+                if count > 0xFF - 2 * SUBCONTEXT_SYMBOL_DELTA
+                    || context.total as u32 > RANGE_CODER_BOTTOM - 0x100
+                {
+                    self.rescale_context(subcontext, 0);
+                }
+                decoded = Some((symbol, value));
+                break;
+            }
+
+            let (bottom, value) = match decoded {
+                Some(decoded) => decoded,
+                None => {
+                    let context = self.symbols[root];
+                    let code = decoder.read(context.total)?;
+                    if code < context.escapes {
+                        decoder.decode(0, context.escapes.into());
+                        break;
+                    }
+
+                    let (symbol, value, under, count) = self.decode_context(
+                        root,
+                        code - context.escapes,
+                        CONTEXT_SYMBOL_DELTA,
+                        CONTEXT_SYMBOL_MINIMUM,
+                        true,
+                    )?;
+                    decoder.decode(context.escapes as u32 + under as u32, count.into());
+
+                    let context = &mut self.symbols[root];
+                    context.total = context.total.wrapping_add(CONTEXT_SYMBOL_DELTA);
+                    if count > 0xFF - 2 * CONTEXT_SYMBOL_DELTA + CONTEXT_SYMBOL_MINIMUM
+                        || context.total as u32 > RANGE_CODER_BOTTOM - 0x100
+                    {
+                        self.rescale_context(root, CONTEXT_SYMBOL_MINIMUM);
+                    }
+                    (symbol, value)
+                }
+            };
+
+            // Teach the higher order contexts that escaped about the decoded value
+            let mut patch = predicted as usize;
+            while patch != subcontext {
+                let (symbol, _, count) =
+                    self.encode_context(patch, value, SUBCONTEXT_SYMBOL_DELTA, 0);
+                self.set_parent(parent, &mut predicted, symbol);
+                parent = ParentSlot::Symbol(symbol);
+
+                let context = &mut self.symbols[patch];
+                if count == 0 {
+                    context.escapes = context.escapes.wrapping_add(SUBCONTEXT_ESCAPE_DELTA);
+                    context.total = context.total.wrapping_add(SUBCONTEXT_ESCAPE_DELTA);
+                }
+                context.total = context.total.wrapping_add(SUBCONTEXT_SYMBOL_DELTA);
+                if count > 0xFF - 2 * SUBCONTEXT_SYMBOL_DELTA
+                    || context.total as u32 > RANGE_CODER_BOTTOM - 0x100
+                {
+                    self.rescale_context(patch, 0);
+                }
+                patch = self.symbols[patch].parent as usize;
+            }
+            self.set_parent(parent, &mut predicted, bottom);
+
+            if output.len() >= limit {
+                return None;
+            }
+            output.push(value);
+
+            if order >= SUBCONTEXT_ORDER {
+                predicted = self.symbols[predicted as usize].parent;
+            } else {
+                order += 1;
+            }
+            self.free_symbols(&mut root, &mut predicted, &mut order);
+        }
+
+        Some(output)
+    }
+}
+
+struct Encoder {
+    low: u32,
+    range: u32,
+    output: Vec<u8>,
+    limit: usize,
+}
+
+impl Encoder {
+    fn output(&mut self, value: u8) -> Option<()> {
+        if self.output.len() >= self.limit {
+            return None;
+        }
+        self.output.push(value);
+        Some(())
+    }
+
+    fn encode(&mut self, under: u32, count: u32, total: u32) -> Option<()> {
+        self.range /= total;
+        self.low = self.low.wrapping_add(under.wrapping_mul(self.range));
+        self.range = self.range.wrapping_mul(count);
+        loop {
+            if (self.low ^ self.low.wrapping_add(self.range)) >= RANGE_CODER_TOP {
+                if self.range >= RANGE_CODER_BOTTOM {
+                    return Some(());
+                }
+                self.range = self.low.wrapping_neg() & (RANGE_CODER_BOTTOM - 1);
+            }
+            self.output((self.low >> 24) as u8)?;
+            self.range <<= 8;
+            self.low <<= 8;
+        }
+    }
+
+    fn flush(&mut self) -> Option<()> {
+        while self.low != 0 {
+            self.output((self.low >> 24) as u8)?;
+            self.low <<= 8;
+        }
+        Some(())
+    }
+}
+
+struct Decoder<'a> {
+    low: u32,
+    code: u32,
+    range: u32,
+    input: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn next_byte(&mut self) -> u32 {
+        match self.input.split_first() {
+            Some((&byte, rest)) => {
+                self.input = rest;
+                byte.into()
+            }
+            None => 0,
+        }
+    }
+
+    fn seed(&mut self) {
+        for shift in [24, 16, 8, 0] {
+            self.code |= self.next_byte() << shift;
+        }
+    }
+
+    /// Returns None instead of dividing by zero on corrupt input
+    fn read(&mut self, total: u16) -> Option<u16> {
+        self.range = self.range.checked_div(total.into())?;
+        let code = self.code.wrapping_sub(self.low).checked_div(self.range)?;
+        Some(code as u16)
+    }
+
+    fn decode(&mut self, under: u32, count: u32) {
+        self.low = self.low.wrapping_add(under.wrapping_mul(self.range));
+        self.range = self.range.wrapping_mul(count);
+        loop {
+            if (self.low ^ self.low.wrapping_add(self.range)) >= RANGE_CODER_TOP {
+                if self.range >= RANGE_CODER_BOTTOM {
+                    return;
+                }
+                self.range = self.low.wrapping_neg() & (RANGE_CODER_BOTTOM - 1);
+            }
+            self.code = (self.code << 8) | self.next_byte();
+            self.range <<= 8;
+            self.low <<= 8;
+        }
+    }
+}
@@ -8,6 +8,17 @@ pub struct EnetDeserializer<B: Buf> {
     pub consumed: usize,
 }
 
+impl<B: Buf> EnetDeserializer<B> {
+    /// Counts the bytes about to be read, failing if the input is too short for them
+    fn advance(&mut self, size: usize) -> Result<(), EncodingError> {
+        if self.input.remaining() < size {
+            return Err(EncodingError::NotEnoughData(self.input.remaining(), size));
+        }
+        self.consumed += size;
+        Ok(())
+    }
+}
+
 impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     type Error = EncodingError;
 
@@ -22,7 +33,7 @@ impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.consumed += 1;
+        self.advance(1)?;
         visitor.visit_bool(self.input.get_u8() != 0)
     }
 
@@ -30,7 +41,7 @@ impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.consumed += 1;
+        self.advance(1)?;
         visitor.visit_i8(self.input.get_i8())
     }
 
@@ -38,7 +49,7 @@ impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.consumed += 2;
+        self.advance(2)?;
         visitor.visit_i16(self.input.get_i16())
     }
 
@@ -46,7 +57,7 @@ impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.consumed += 4;
+        self.advance(4)?;
         visitor.visit_i32(self.input.get_i32())
     }
 
@@ -54,7 +65,7 @@ impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.consumed += 8;
+        self.advance(8)?;
         visitor.visit_i64(self.input.get_i64())
     }
 
@@ -62,7 +73,7 @@ impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.consumed += 1;
+        self.advance(1)?;
         visitor.visit_u8(self.input.get_u8())
     }
 
@@ -70,7 +81,7 @@ impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.consumed += 2;
+        self.advance(2)?;
         visitor.visit_u16(self.input.get_u16())
     }
 
@@ -78,7 +89,7 @@ impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.consumed += 4;
+        self.advance(4)?;
         visitor.visit_u32(self.input.get_u32())
     }
 
@@ -86,7 +97,7 @@ impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.consumed += 8;
+        self.advance(8)?;
         visitor.visit_u64(self.input.get_u64())
     }
 
@@ -94,7 +105,7 @@ impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.consumed += 4;
+        self.advance(4)?;
         visitor.visit_f32(self.input.get_f32())
     }
 
@@ -102,7 +113,7 @@ impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.consumed += 8;
+        self.advance(8)?;
         visitor.visit_f64(self.input.get_f64())
     }
 
@@ -110,7 +121,7 @@ impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.consumed += 1;
+        self.advance(1)?;
         let c: char =
             char::from_u32(self.input.get_u8().into()).ok_or(EncodingError::CustomError)?;
         visitor.visit_char(c)
@@ -184,8 +195,8 @@ impl<'de, 'a, B: Buf> Deserializer<'de> for &'a mut EnetDeserializer<B> {
     where
         V: serde::de::Visitor<'de>,
     {
+        self.advance(2)?;
         let len: usize = self.input.get_u16().into();
-        self.consumed += 2;
         if self.input.remaining() < len {
             return Err(EncodingError::NotEnoughData(self.input.remaining(), len));
         }
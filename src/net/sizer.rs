@@ -159,6 +159,7 @@ impl<'a> Serializer for &'a mut EnetSizer {
     }
 
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        self.size += 2;
         Ok(self)
     }
 
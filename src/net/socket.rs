@@ -1,12 +1,17 @@
-use std::{collections::VecDeque, net::SocketAddr};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+};
 
 use async_trait::async_trait;
-use bytes::{Bytes, BytesMut};
+use bytes::BytesMut;
 use serde::{Deserialize, Serialize};
 use tokio::{net::UdpSocket, time::Duration};
 
 use crate::{
+    consts::{PROTOCOL_MAXIMUM_MTU, PROTOCOL_MAXIMUM_PACKET_COMMANDS, PROTOCOL_MAXIMUM_PEER_ID},
     error::{ENetError, Result},
+    peer::PeerID,
     protocol::{
         AcknowledgeCommand, BandwidthLimitCommand, Command, CommandInfo, ConnectCommand,
         DisconnectCommand, PacketFlags, PingCommand, ProtocolCommand, ProtocolCommandHeader,
@@ -16,39 +21,162 @@ use crate::{
     },
 };
 
-use super::{deserializer::EnetDeserializer, serializer::EnetSerializer, time::PacketTime};
+use super::{
+    checksum::crc32, compress::Compressor, deserializer::EnetDeserializer,
+    serializer::EnetSerializer, sizer::EnetSizer, stats::SocketStats, time::PacketTime,
+};
+
+/// Bytes taken by a command header
+const COMMAND_HEADER_SIZE: usize = 4;
+/// Bytes taken by the protocol header, including the sent time
+const PROTOCOL_HEADER_SIZE: usize = 4;
+/// Bytes the checksum adds to the protocol header
+pub(crate) const CHECKSUM_SIZE: usize = 4;
+
+/// Bytes a command takes up in a datagram, including its command header
+pub(crate) fn command_size(command: &ProtocolCommand) -> Result<usize> {
+    let mut sizer = EnetSizer {
+        size: COMMAND_HEADER_SIZE,
+    };
+    match command {
+        ProtocolCommand::Ack(l) => l.serialize(&mut sizer),
+        ProtocolCommand::Connect(l) => l.serialize(&mut sizer),
+        ProtocolCommand::VerifyConnect(l) => l.serialize(&mut sizer),
+        ProtocolCommand::Disconnect(l) => l.serialize(&mut sizer),
+        ProtocolCommand::Ping(l) => l.serialize(&mut sizer),
+        ProtocolCommand::SendReliable(l) => l.serialize(&mut sizer),
+        ProtocolCommand::SendUnreliable(l) => l.serialize(&mut sizer),
+        ProtocolCommand::SendFragment(l) => l.serialize(&mut sizer),
+        ProtocolCommand::SendUnsequenced(l) => l.serialize(&mut sizer),
+        ProtocolCommand::BandwidthLimit(l) => l.serialize(&mut sizer),
+        ProtocolCommand::ThrottleConfigure(l) => l.serialize(&mut sizer),
+        ProtocolCommand::SendUnreliableFragment(l) => l.serialize(&mut sizer),
+        ProtocolCommand::None | ProtocolCommand::Count => Ok(()),
+    }?;
+    Ok(sizer.size)
+}
 
 #[async_trait]
 pub trait Socket {
     async fn recv(&mut self) -> Result<Command>;
     async fn send(&mut self, command: &Command) -> Result<()>;
+
+    /// Sends commands bound for a single peer, packing as many as fit within the mtu into
+    /// each datagram
+    async fn send_packed(&mut self, commands: &[Command], _mtu: usize) -> Result<()> {
+        for command in commands {
+            self.send(command).await?;
+        }
+        Ok(())
+    }
+
+    /// Compresses the commands of every datagram sent from now on
+    fn set_compressor(&mut self, _compressor: Box<dyn Compressor>) {}
+
+    /// Adds ENet's CRC32 checksum to every datagram sent and checks it on every one received
+    fn enable_checksum(&mut self) {}
+
+    /// Sets the connect id a peer's checksums are computed with, `None` once the peer is gone
+    fn set_connect_id(&mut self, _peer_id: PeerID, _connect_id: Option<u32>) {}
+
+    /// Traffic counted since the socket was created or its stats were reset
+    fn stats(&self) -> SocketStats {
+        SocketStats::default()
+    }
+
+    fn reset_stats(&mut self) {}
 }
 
 #[derive(Debug)]
 pub struct ENetSocket {
     pub socket: UdpSocket,
-    buf: [u8; 1024],
+    /// One byte larger than the maximum mtu, so oversized datagrams are noticed instead of
+    /// being truncated
+    buf: [u8; PROTOCOL_MAXIMUM_MTU + 1],
     incoming_queue: VecDeque<Command>,
+    compressor: Option<Box<dyn Compressor>>,
+    checksum: bool,
+    connect_ids: HashMap<PeerID, u32>,
+    stats: SocketStats,
 }
 
 #[async_trait]
 impl Socket for ENetSocket {
     async fn recv(&mut self) -> Result<Command> {
-        if let Some(c) = self.incoming_queue.pop_front() {
-            return Ok(c);
+        loop {
+            if let Some(c) = self.incoming_queue.pop_front() {
+                return Ok(c);
+            }
+            let (len, addr) = self.socket.recv_from(&mut self.buf).await?;
+            self.stats.datagrams_received += 1;
+            self.stats.bytes_received += len as u64;
+            if len > PROTOCOL_MAXIMUM_MTU {
+                self.stats.malformed_datagrams += 1;
+                tracing::debug!("Dropping datagram from {addr} larger than the maximum mtu");
+                continue;
+            }
+            // Commands before the malformed one were already queued, like ENet handles them
+            if let Err(e) = self.deserialize_datagram(addr, len) {
+                self.stats.malformed_datagrams += 1;
+                tracing::debug!("Dropping malformed datagram from {addr}: {e}");
+            }
         }
-        let (len, addr) = self.socket.recv_from(&mut self.buf).await?;
-        self.deserialize_command(addr, len)
     }
 
     async fn send(&mut self, command: &Command) -> Result<()> {
-        let addr = command.info.addr;
-        let (bytes, size) = self.serialize_command(command)?;
+        self.send_datagram(std::slice::from_ref(command)).await
+    }
 
-        let bytes = &bytes[0..size];
-        self.socket.send_to(bytes, addr).await?;
+    async fn send_packed(&mut self, commands: &[Command], mtu: usize) -> Result<()> {
+        let mut start = 0;
+        while start < commands.len() {
+            let mut size = self.header_size();
+            let mut end = start;
+            while end < commands.len() && end - start < PROTOCOL_MAXIMUM_PACKET_COMMANDS {
+                let next = command_size(&commands[end].command)?;
+                if size + next > mtu {
+                    // Commands are fragmented to the mtu, so one that overflows it on its own is
+                    // a bug, which is still better sent alone than losing the whole batch over
+                    debug_assert!(
+                        end > start,
+                        "{next} byte command exceeds the {mtu} byte mtu"
+                    );
+                    if end > start {
+                        break;
+                    }
+                }
+                size += next;
+                end += 1;
+            }
+
+            self.send_datagram(&commands[start..end]).await?;
+            start = end;
+        }
         Ok(())
     }
+
+    fn set_compressor(&mut self, compressor: Box<dyn Compressor>) {
+        self.compressor = Some(compressor);
+    }
+
+    fn enable_checksum(&mut self) {
+        self.checksum = true;
+    }
+
+    fn set_connect_id(&mut self, peer_id: PeerID, connect_id: Option<u32>) {
+        match connect_id {
+            Some(connect_id) => self.connect_ids.insert(peer_id, connect_id),
+            None => self.connect_ids.remove(&peer_id),
+        };
+    }
+
+    fn stats(&self) -> SocketStats {
+        self.stats
+    }
+
+    fn reset_stats(&mut self) {
+        self.stats = SocketStats::default();
+    }
 }
 
 impl From<UdpSocket> for ENetSocket {
@@ -61,15 +189,69 @@ impl ENetSocket {
     pub fn new(socket: UdpSocket) -> Self {
         ENetSocket {
             socket,
-            buf: [0; 1024],
+            buf: [0; PROTOCOL_MAXIMUM_MTU + 1],
             incoming_queue: Default::default(),
+            compressor: None,
+            checksum: false,
+            connect_ids: Default::default(),
+            stats: SocketStats::default(),
+        }
+    }
+
+    /// Bytes taken by the protocol header, including the checksum when enabled
+    fn header_size(&self) -> usize {
+        if self.checksum {
+            PROTOCOL_HEADER_SIZE + CHECKSUM_SIZE
+        } else {
+            PROTOCOL_HEADER_SIZE
+        }
+    }
+
+    /// The value a checksum is computed with in its own place, which is 0 until the remote
+    /// assigned us a peer id
+    fn checksum_seed(&self, remote_peer_id: PeerID, peer_id: PeerID) -> u32 {
+        if remote_peer_id.0 as usize >= PROTOCOL_MAXIMUM_PEER_ID {
+            return 0;
         }
+        self.connect_ids.get(&peer_id).copied().unwrap_or(0)
     }
 
-    fn deserialize_command(&mut self, addr: SocketAddr, len: usize) -> Result<Command> {
-        let buf = &self.buf[..];
+    async fn send_datagram(&mut self, commands: &[Command]) -> Result<()> {
+        let Some(first) = commands.first() else {
+            return Ok(());
+        };
+        let addr = first.info.addr;
+        let (mut buff, header_size, size) = self.serialize_commands(commands)?;
+        let compressed = self.compress(&buff[header_size..size]);
+        if compressed.is_some() {
+            // Sets the compressed flag of the peer id
+            buff[0] |= 0x40;
+        }
+
+        if self.checksum {
+            // Covers the header flags and the commands before compression
+            let checksum = crc32(&buff[..size]);
+            buff[header_size - CHECKSUM_SIZE..header_size].copy_from_slice(&checksum.to_be_bytes());
+        }
+
+        buff.truncate(size);
+        if let Some(compressed) = compressed {
+            buff.truncate(header_size);
+            buff.extend_from_slice(&compressed);
+        }
+        self.socket.send_to(&buff, addr).await?;
+        self.stats.datagrams_sent += 1;
+        self.stats.bytes_sent += buff.len() as u64;
+        for command in commands {
+            self.stats.commands_sent.count(&command.command);
+        }
+        Ok(())
+    }
+
+    /// Queues every command within a datagram, dropping it if the checksum does not match
+    fn deserialize_datagram(&mut self, addr: SocketAddr, len: usize) -> Result<()> {
         let mut deser = EnetDeserializer {
-            input: buf,
+            input: &self.buf[..len],
             consumed: 0,
         };
 
@@ -89,6 +271,50 @@ impl ENetSocket {
 
         let header = ProtocolHeader { peer_id, sent_time };
 
+        let header_size = deser.consumed + if self.checksum { CHECKSUM_SIZE } else { 0 };
+        if len < header_size {
+            return Err(ENetError::InvalidPacket());
+        }
+
+        // The datagram with its commands decompressed
+        let mut datagram = [0; PROTOCOL_MAXIMUM_MTU];
+        datagram[..header_size].copy_from_slice(&self.buf[..header_size]);
+        let len = if is_compressed {
+            let data = self
+                .compressor
+                .as_mut()
+                .and_then(|c| {
+                    c.decompress(
+                        &self.buf[header_size..len],
+                        PROTOCOL_MAXIMUM_MTU - header_size,
+                    )
+                })
+                .filter(|data| !data.is_empty())
+                .ok_or(ENetError::InvalidPacket())?;
+            datagram[header_size..header_size + data.len()].copy_from_slice(&data);
+            header_size + data.len()
+        } else {
+            datagram[header_size..len].copy_from_slice(&self.buf[header_size..len]);
+            len
+        };
+
+        if self.checksum {
+            let slot = header_size - CHECKSUM_SIZE..header_size;
+            let checksum = u32::from_be_bytes(datagram[slot.clone()].try_into().unwrap());
+            let seed = self.checksum_seed(peer_id.into(), peer_id.into());
+            datagram[slot].copy_from_slice(&seed.to_be_bytes());
+            if crc32(&datagram[..len]) != checksum {
+                self.stats.checksum_failures += 1;
+                tracing::debug!("Dropping datagram from {addr} with a mismatched checksum");
+                return Ok(());
+            }
+        }
+
+        let mut deser = EnetDeserializer {
+            input: &datagram[header_size..len],
+            consumed: header_size,
+        };
+
         while deser.consumed < len {
             let header = header.clone();
 
@@ -120,7 +346,7 @@ impl ENetSocket {
 
                 // TODO impl these flags
                 no_allocate: false,
-                unreliable_fragment: false,
+                unreliable_fragment: matches!(packet, ProtocolCommand::SendUnreliableFragment(_)),
                 sent: false,
                 is_compressed,
                 send_time,
@@ -136,86 +362,112 @@ impl ENetSocket {
                 sent_time: Duration::from_millis(header.sent_time.into()),
                 session_id,
             };
+            self.stats.commands_received.count(&packet);
             self.incoming_queue.push_back(Command {
                 command: packet,
                 info,
             });
         }
 
-        Ok(self.incoming_queue.pop_front().unwrap())
+        Ok(())
+    }
+
+    /// Compresses the commands of a datagram, unless that would not make them smaller
+    fn compress(&mut self, commands: &[u8]) -> Option<Vec<u8>> {
+        let compressed = self
+            .compressor
+            .as_mut()?
+            .compress(commands, commands.len())?;
+        (!compressed.is_empty() && compressed.len() < commands.len()).then_some(compressed)
     }
 
-    fn serialize_command(&self, p: &Command) -> Result<(Bytes, usize)> {
-        let mut buff = BytesMut::zeroed(1024);
+    /// Writes one protocol header, taken from the first command, followed by every command
+    fn serialize_commands(&self, commands: &[Command]) -> Result<(BytesMut, usize, usize)> {
+        let mut capacity = self.header_size();
+        for command in commands {
+            capacity += command_size(&command.command)?;
+        }
+        if capacity > PROTOCOL_MAXIMUM_MTU {
+            return Err(ENetError::PacketTooLarge(capacity));
+        }
+
+        let mut buff = BytesMut::zeroed(capacity);
         let mut ser = EnetSerializer {
             output: &mut buff[..],
             size: 0,
         };
 
-        let flags = &p.info.flags;
-        let id_flags: u16 = p.info.session_id;
-        let id_flags = id_flags << 12
-            | if flags.send_time { 1 << 15 } else { 0 }
-            | if flags.is_compressed { 1 << 14 } else { 0 };
+        let header = &commands[0].info;
+        let send_time = commands.iter().any(|c| c.info.flags.send_time);
+        let id_flags: u16 = header.session_id;
+        let id_flags = id_flags << 12 | if send_time { 1 << 15 } else { 0 };
 
-        let peer_id: u16 = p.info.peer_id.into();
+        let peer_id: u16 = header.peer_id.into();
         let peer_id = peer_id | id_flags;
 
         peer_id.serialize(&mut ser)?;
 
-        if flags.send_time {
-            let sent_time = PacketTime::from_duration(&p.info.sent_time);
+        if send_time {
+            let sent_time = PacketTime::from_duration(&header.sent_time);
             sent_time.serialize(&mut ser)?;
         }
+        if self.checksum {
+            self.checksum_seed(header.peer_id, header.internal_peer_id)
+                .serialize(&mut ser)?;
+        }
+        let header_size = ser.size;
+
+        for p in commands {
+            let flags = &p.info.flags;
+            let command_flags = if flags.reliable { 1 << 7 } else { 0 }
+                | if flags.unsequenced { 1 << 6 } else { 0 };
+
+            let cmd_type = match p.command {
+                ProtocolCommand::None => 0,
+                ProtocolCommand::Ack(_) => 1,
+                ProtocolCommand::Connect(_) => 2,
+                ProtocolCommand::VerifyConnect(_) => 3,
+                ProtocolCommand::Disconnect(_) => 4,
+                ProtocolCommand::Ping(_) => 5,
+                ProtocolCommand::SendReliable(_) => 6,
+                ProtocolCommand::SendUnreliable(_) => 7,
+                ProtocolCommand::SendFragment(_) => 8,
+                ProtocolCommand::SendUnsequenced(_) => 9,
+                ProtocolCommand::BandwidthLimit(_) => 10,
+                ProtocolCommand::ThrottleConfigure(_) => 11,
+                ProtocolCommand::SendUnreliableFragment(_) => 12,
+                ProtocolCommand::Count => 13,
+            };
 
-        let command_flags =
-            if flags.reliable { 1 << 7 } else { 0 } | if flags.unsequenced { 1 << 6 } else { 0 };
-
-        let cmd_type = match p.command {
-            ProtocolCommand::None => 0,
-            ProtocolCommand::Ack(_) => 1,
-            ProtocolCommand::Connect(_) => 2,
-            ProtocolCommand::VerifyConnect(_) => 3,
-            ProtocolCommand::Disconnect(_) => 4,
-            ProtocolCommand::Ping(_) => 5,
-            ProtocolCommand::SendReliable(_) => 6,
-            ProtocolCommand::SendUnreliable(_) => 7,
-            ProtocolCommand::SendFragment(_) => 8,
-            ProtocolCommand::SendUnsequenced(_) => 9,
-            ProtocolCommand::BandwidthLimit(_) => 10,
-            ProtocolCommand::ThrottleConfigure(_) => 11,
-            ProtocolCommand::SendUnreliableFragment(_) => 12,
-            ProtocolCommand::Count => 13,
-        };
-
-        let command = cmd_type | command_flags;
+            let command = cmd_type | command_flags;
 
-        let command_header = ProtocolCommandHeader {
-            command,
-            channel_id: p.info.channel_id,
-            reliable_sequence_number: p.info.reliable_sequence_number,
-        };
+            let command_header = ProtocolCommandHeader {
+                command,
+                channel_id: p.info.channel_id,
+                reliable_sequence_number: p.info.reliable_sequence_number,
+            };
 
-        command_header.serialize(&mut ser)?;
-
-        match &p.command {
-            ProtocolCommand::Ack(l) => l.serialize(&mut ser),
-            ProtocolCommand::Connect(l) => l.serialize(&mut ser),
-            ProtocolCommand::VerifyConnect(l) => l.serialize(&mut ser),
-            ProtocolCommand::Disconnect(l) => l.serialize(&mut ser),
-            ProtocolCommand::Ping(l) => l.serialize(&mut ser),
-            ProtocolCommand::SendReliable(l) => l.serialize(&mut ser),
-            ProtocolCommand::SendUnreliable(l) => l.serialize(&mut ser),
-            ProtocolCommand::SendFragment(l) => l.serialize(&mut ser),
-            ProtocolCommand::SendUnsequenced(l) => l.serialize(&mut ser),
-            ProtocolCommand::BandwidthLimit(l) => l.serialize(&mut ser),
-            ProtocolCommand::ThrottleConfigure(l) => l.serialize(&mut ser),
-            ProtocolCommand::SendUnreliableFragment(l) => l.serialize(&mut ser),
-            ProtocolCommand::Count => Ok(()),
-            _ => Ok(()),
-        }?;
+            command_header.serialize(&mut ser)?;
+
+            match &p.command {
+                ProtocolCommand::Ack(l) => l.serialize(&mut ser),
+                ProtocolCommand::Connect(l) => l.serialize(&mut ser),
+                ProtocolCommand::VerifyConnect(l) => l.serialize(&mut ser),
+                ProtocolCommand::Disconnect(l) => l.serialize(&mut ser),
+                ProtocolCommand::Ping(l) => l.serialize(&mut ser),
+                ProtocolCommand::SendReliable(l) => l.serialize(&mut ser),
+                ProtocolCommand::SendUnreliable(l) => l.serialize(&mut ser),
+                ProtocolCommand::SendFragment(l) => l.serialize(&mut ser),
+                ProtocolCommand::SendUnsequenced(l) => l.serialize(&mut ser),
+                ProtocolCommand::BandwidthLimit(l) => l.serialize(&mut ser),
+                ProtocolCommand::ThrottleConfigure(l) => l.serialize(&mut ser),
+                ProtocolCommand::SendUnreliableFragment(l) => l.serialize(&mut ser),
+                ProtocolCommand::Count => Ok(()),
+                _ => Ok(()),
+            }?;
+        }
 
         let size = ser.size;
-        Ok((buff.freeze(), size))
+        Ok((buff, header_size, size))
     }
 }
@@ -0,0 +1,73 @@
+//! Traffic counters kept by a socket
+
+use crate::protocol::ProtocolCommand;
+
+/// Datagrams and commands a socket sent and received
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketStats {
+    pub datagrams_sent: u64,
+    pub datagrams_received: u64,
+    /// Bytes on the wire, after compression
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Datagrams dropped because they were too large or could not be deserialized
+    pub malformed_datagrams: u64,
+    /// Datagrams dropped because their checksum did not match
+    pub checksum_failures: u64,
+    pub commands_sent: CommandCounts,
+    pub commands_received: CommandCounts,
+}
+
+/// Commands counted by their type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandCounts {
+    pub acknowledge: u64,
+    pub connect: u64,
+    pub verify_connect: u64,
+    pub disconnect: u64,
+    pub ping: u64,
+    pub send_reliable: u64,
+    pub send_unreliable: u64,
+    pub send_fragment: u64,
+    pub send_unsequenced: u64,
+    pub bandwidth_limit: u64,
+    pub throttle_configure: u64,
+    pub send_unreliable_fragment: u64,
+}
+
+impl CommandCounts {
+    pub(crate) fn count(&mut self, command: &ProtocolCommand) {
+        let counter = match command {
+            ProtocolCommand::Ack(_) => &mut self.acknowledge,
+            ProtocolCommand::Connect(_) => &mut self.connect,
+            ProtocolCommand::VerifyConnect(_) => &mut self.verify_connect,
+            ProtocolCommand::Disconnect(_) => &mut self.disconnect,
+            ProtocolCommand::Ping(_) => &mut self.ping,
+            ProtocolCommand::SendReliable(_) => &mut self.send_reliable,
+            ProtocolCommand::SendUnreliable(_) => &mut self.send_unreliable,
+            ProtocolCommand::SendFragment(_) => &mut self.send_fragment,
+            ProtocolCommand::SendUnsequenced(_) => &mut self.send_unsequenced,
+            ProtocolCommand::BandwidthLimit(_) => &mut self.bandwidth_limit,
+            ProtocolCommand::ThrottleConfigure(_) => &mut self.throttle_configure,
+            ProtocolCommand::SendUnreliableFragment(_) => &mut self.send_unreliable_fragment,
+            ProtocolCommand::None | ProtocolCommand::Count => return,
+        };
+        *counter += 1;
+    }
+
+    /// Commands of every type
+    pub fn total(&self) -> u64 {
+        self.acknowledge
+            + self.connect
+            + self.verify_connect
+            + self.disconnect
+            + self.ping
+            + self.send_reliable
+            + self.send_unreliable
+            + self.send_fragment
+            + self.send_unsequenced
+            + self.bandwidth_limit
+            + self.throttle_configure
+            + self.send_unreliable_fragment
+    }
+}
@@ -1 +1,297 @@
+use std::time::Duration;
 
+use bytes::BytesMut;
+use quickcheck::quickcheck;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+
+use super::{
+    checksum::crc32,
+    compress::{Compressor, RangeCoder},
+    deserializer::EnetDeserializer,
+    serializer::EnetSerializer,
+    socket::{command_size, ENetSocket, Socket},
+};
+use crate::{
+    peer::PeerID,
+    protocol::{
+        Command, CommandInfo, PacketFlags, ProtocolCommand, SendFragmentCommand,
+        SendReliableCommand, SendUnreliableCommand,
+    },
+};
+
+#[test]
+fn fragment_round_trip() {
+    let fragment = SendFragmentCommand {
+        start_sequence_number: 7,
+        fragment_count: 2,
+        fragment_number: 1,
+        total_length: 1003,
+        fragment_offset: 1000,
+        data: vec![1, 2, 3],
+    };
+
+    let mut buff = BytesMut::zeroed(64);
+    let mut ser = EnetSerializer {
+        output: &mut buff[..],
+        size: 0,
+    };
+    fragment.serialize(&mut ser).unwrap();
+    let size = ser.size;
+
+    // The data length sits after the start sequence and the payload has no prefix
+    assert_eq!(size, 20 + 3);
+    assert_eq!(&buff[2..4], &[0, 3]);
+    assert_eq!(&buff[20..23], &[1, 2, 3]);
+
+    let mut deser = EnetDeserializer {
+        input: &buff[..size],
+        consumed: 0,
+    };
+    let out = SendFragmentCommand::deserialize(&mut deser).unwrap();
+    assert_eq!(size, deser.consumed);
+    assert_eq!(fragment, out);
+}
+
+#[test]
+fn command_size_matches_serializer() {
+    let reliable = SendReliableCommand { data: vec![0; 10] };
+    let fragment = SendFragmentCommand {
+        start_sequence_number: 1,
+        fragment_count: 1,
+        fragment_number: 0,
+        total_length: 10,
+        fragment_offset: 0,
+        data: vec![0; 10],
+    };
+
+    assert_eq!(
+        command_size(&ProtocolCommand::SendReliable(reliable)).unwrap(),
+        4 + 2 + 10
+    );
+    assert_eq!(
+        command_size(&ProtocolCommand::SendFragment(fragment)).unwrap(),
+        4 + 20 + 10
+    );
+}
+
+#[tokio::test]
+async fn send_packed_fills_datagrams() {
+    let mut sender = ENetSocket::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = receiver.local_addr().unwrap();
+
+    let commands: Vec<_> = (1..=3)
+        .map(|seq| Command {
+            info: CommandInfo {
+                addr,
+                flags: PacketFlags::default(),
+                internal_peer_id: PeerID(0),
+                peer_id: PeerID(0),
+                channel_id: 0,
+                session_id: 0,
+                reliable_sequence_number: 0,
+                sent_time: Duration::ZERO,
+            },
+            command: SendUnreliableCommand {
+                unreliable_sequence_number: seq,
+                data: vec![0; 200],
+            }
+            .into(),
+        })
+        .collect();
+
+    // Two commands fit in the mtu, the third starts a new datagram
+    sender.send_packed(&commands, 576).await.unwrap();
+
+    let mut buf = [0; 1500];
+    let (first, _) = receiver.recv_from(&mut buf).await.unwrap();
+    let (second, _) = receiver.recv_from(&mut buf).await.unwrap();
+    let command_length = 4 + 4 + 200;
+    assert_eq!(first, 4 + 2 * command_length);
+    assert_eq!(second, 4 + command_length);
+}
+
+#[test]
+fn range_coder_matches_enet() {
+    let input = b"hello hello hello hello world";
+    let compressed = RangeCoder::new().compress(input, 1400).unwrap();
+
+    // Output of enet_range_coder_compress for the same input
+    assert_eq!(
+        compressed,
+        [
+            0x68, 0xfb, 0xe0, 0xc3, 0x3d, 0x70, 0x72, 0xbd, 0xd5, 0xef, 0xfa, 0x6d, 0x70, 0x0c,
+            0xf2, 0x43
+        ]
+    );
+    assert_eq!(
+        RangeCoder::new().decompress(&compressed, 1400).unwrap(),
+        input
+    );
+    assert!(RangeCoder::new().compress(input, 8).is_none());
+}
+
+/// Whether the input comes back unchanged from compressing and decompressing it
+fn range_coder_round_trips(input: &[u8]) -> bool {
+    match RangeCoder::new().compress(input, 2 * input.len() + 16) {
+        Some(compressed) => {
+            RangeCoder::new()
+                .decompress(&compressed, input.len())
+                .as_deref()
+                == Some(input)
+        }
+        // Empty datagrams are never sent compressed
+        None => input.is_empty(),
+    }
+}
+
+quickcheck! {
+    fn range_coder_round_trip(input: Vec<u8>) -> bool {
+        range_coder_round_trips(&input)
+    }
+}
+
+#[test]
+fn range_coder_round_trips_past_the_mtu() {
+    // Xorshift noise, which the coder cannot shrink
+    let mut state = 0x2545_F491_u32;
+    let noise: Vec<u8> = (0..4000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    let text = b"larger than the mtu ".repeat(500);
+
+    assert!(range_coder_round_trips(&[]));
+    assert!(range_coder_round_trips(&noise));
+    assert!(range_coder_round_trips(&text));
+    // Left for the socket to send uncompressed
+    assert!(RangeCoder::new().compress(&noise, noise.len()).is_none());
+}
+
+#[test]
+fn crc32_matches_enet() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(crc32(b""), 0);
+}
+
+#[tokio::test]
+async fn mismatched_checksums_are_dropped() {
+    let mut sender = ENetSocket::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let mut receiver = ENetSocket::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    sender.enable_checksum();
+    receiver.enable_checksum();
+    sender.set_connect_id(PeerID(0), Some(0x1234_5678));
+    receiver.set_connect_id(PeerID(1), Some(0x1234_5678));
+    let addr = receiver.socket.local_addr().unwrap();
+
+    let command = Command {
+        info: CommandInfo {
+            addr,
+            flags: PacketFlags::default(),
+            internal_peer_id: PeerID(0),
+            peer_id: PeerID(1),
+            channel_id: 0,
+            session_id: 0,
+            reliable_sequence_number: 0,
+            sent_time: Duration::ZERO,
+        },
+        command: SendUnreliableCommand {
+            unreliable_sequence_number: 1,
+            data: vec![1, 2, 3],
+        }
+        .into(),
+    };
+
+    // A ping for peer 1 with a bogus checksum
+    let corrupted = [0x80, 0x01, 0, 0, 0xDE, 0xAD, 0xBE, 0xEF, 5, 0xFF, 0, 1];
+    sender.socket.send_to(&corrupted, addr).await.unwrap();
+    sender.send(&command).await.unwrap();
+
+    let received = receiver.recv().await.unwrap();
+    assert!(matches!(
+        received.command,
+        ProtocolCommand::SendUnreliable(SendUnreliableCommand { ref data, .. }) if data == &[1, 2, 3]
+    ));
+    assert_eq!(receiver.stats().checksum_failures, 1);
+}
+
+#[tokio::test]
+async fn oversized_datagrams_are_rejected() {
+    let mut sender = ENetSocket::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let mut receiver = ENetSocket::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let addr = receiver.socket.local_addr().unwrap();
+
+    let command = |data| Command {
+        info: CommandInfo {
+            addr,
+            flags: PacketFlags::default(),
+            internal_peer_id: PeerID(0),
+            peer_id: PeerID(0),
+            channel_id: 0,
+            session_id: 0,
+            reliable_sequence_number: 0,
+            sent_time: Duration::ZERO,
+        },
+        command: SendUnreliableCommand {
+            unreliable_sequence_number: 1,
+            data,
+        }
+        .into(),
+    };
+
+    // Larger than any mtu, so it is dropped before the next datagram is read
+    sender.socket.send_to(&[0; 5000], addr).await.unwrap();
+    sender.send(&command(vec![1, 2, 3])).await.unwrap();
+
+    let received = receiver.recv().await.unwrap();
+    assert_eq!(received.command.data_length(), 3);
+}
+
+#[tokio::test]
+async fn malformed_datagrams_are_counted() {
+    let mut sender = ENetSocket::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let mut receiver = ENetSocket::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let addr = receiver.socket.local_addr().unwrap();
+    let command = Command {
+        info: CommandInfo {
+            addr,
+            flags: PacketFlags::default(),
+            internal_peer_id: PeerID(0),
+            peer_id: PeerID(0),
+            channel_id: 0,
+            session_id: 0,
+            reliable_sequence_number: 0,
+            sent_time: Duration::ZERO,
+        },
+        command: SendUnreliableCommand {
+            unreliable_sequence_number: 1,
+            data: vec![1, 2, 3],
+        }
+        .into(),
+    };
+
+    // A command type no ENet version sends
+    sender
+        .socket
+        .send_to(&[0x80, 0x00, 0, 0, 0x0F, 0, 0, 1], addr)
+        .await
+        .unwrap();
+    sender.send(&command).await.unwrap();
+
+    let received = receiver.recv().await.unwrap();
+    assert_eq!(received.command.data_length(), 3);
+    let stats = receiver.stats();
+    assert_eq!(stats.datagrams_received, 2);
+    assert_eq!(stats.malformed_datagrams, 1);
+    assert_eq!(stats.commands_received.send_unreliable, 1);
+    assert_eq!(stats.commands_received.total(), 1);
+    assert_eq!(sender.stats().bytes_sent, stats.bytes_received - 8);
+
+    receiver.reset_stats();
+    assert_eq!(receiver.stats(), Default::default());
+}
@@ -24,25 +24,58 @@ impl PacketTime {
         Self(time)
     }
 
+    /// Rebuilds the full send time from its lower 16 bits the way
+    /// `enet_protocol_handle_acknowledge` does, None if it would lie after `curr` or before
+    /// the clock started
     pub fn to_duration(&self, curr: &Duration) -> Option<Duration> {
-        let lower: u16 = self.0;
-        let lower = lower as u64;
         let curr_mill = curr.as_millis() as u64;
-        let curr_mill = curr_mill & 0xFFFF0000;
-        let mut dur: u64 = curr_mill | lower;
+        let mut dur = (curr_mill & !0xFFFF) | self.0 as u64;
 
         if (dur & 0x8000) > (curr_mill & 0x8000) {
-            if dur < 0x10000 {
-                return None;
-            }
-            dur -= 0x10000;
+            dur = dur.checked_sub(0x10000)?;
         }
-
-        let dur = Duration::from_millis(dur);
-        if &dur > curr {
+        if dur > curr_mill {
             return None;
         }
 
-        Some(dur)
+        Some(Duration::from_millis(dur))
+    }
+
+    /// Time since this send time was taken, if it was echoed back by `curr`
+    pub fn elapsed(&self, curr: &Duration) -> Option<Duration> {
+        let sent = self.to_duration(curr)?;
+        Some(Duration::from_millis(curr.as_millis() as u64) - sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use quickcheck::quickcheck;
+
+    use super::PacketTime;
+
+    quickcheck! {
+        /// Round trips shorter than half the 16 bit range come out exact, whichever 65.536s
+        /// boundary they cross
+        fn round_trip_crosses_wraparound(boundary: u32, before: u16, rtt: u16) -> bool {
+            let rtt = (rtt & 0x7FFF) as u64;
+            let sent = (boundary as u64 + 1) * 0x10000 - (before as u64).min(rtt);
+            let now = Duration::from_millis(sent + rtt);
+            let time = PacketTime::from_duration(&Duration::from_millis(sent));
+            time.elapsed(&now) == Some(Duration::from_millis(rtt))
+        }
+
+        /// Send times that would come before the clock started are rejected
+        fn send_time_before_start_is_rejected(now: u16, sent: u16) -> bool {
+            let time = PacketTime::from(sent);
+            let elapsed = time.elapsed(&Duration::from_millis(now as u64));
+            if sent <= now {
+                elapsed == Some(Duration::from_millis((now - sent) as u64))
+            } else {
+                elapsed.is_none()
+            }
+        }
     }
 }
@@ -1,23 +1,49 @@
 mod peer_id;
 pub use peer_id::*;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     net::SocketAddr,
     time::{Duration, Instant},
 };
 
-use tokio::sync::mpsc::Sender;
+use tokio::sync::{mpsc::Sender, watch};
 
 use super::{
     channel::{Channel, ChannelID},
+    consts::{
+        HOST_DEFAULT_MTU, PEER_DEFAULT_PACKET_THROTTLE, PEER_DEFAULT_ROUND_TRIP_TIME,
+        PEER_FREE_UNSEQUENCED_WINDOWS, PEER_PACKET_LOSS_INTERVAL, PEER_PACKET_LOSS_SCALE,
+        PEER_PACKET_THROTTLE_ACCELERATION, PEER_PACKET_THROTTLE_COUNTER,
+        PEER_PACKET_THROTTLE_DECELERATION, PEER_PACKET_THROTTLE_INTERVAL,
+        PEER_PACKET_THROTTLE_SCALE, PEER_UNSEQUENCED_WINDOW_SIZE, PEER_WINDOW_SIZE_SCALE,
+        PROTOCOL_MAXIMUM_PEER_ID, PROTOCOL_MAXIMUM_WINDOW_SIZE, PROTOCOL_MINIMUM_WINDOW_SIZE,
+    },
     error::{ChannelError, ENetError, Result},
     host::hostevents::{HostRecvEvent, HostSendEvent},
-    protocol::PacketFlags,
+    net::socket::CHECKSUM_SIZE,
+    protocol::{Command, PacketFlags, ThrottleConfigureCommand},
 };
 
+/// Bytes taken by the protocol header and a fragment command header
+const FRAGMENT_HEADER_SIZE: usize = 4 + 24;
+
+/// The connection state of a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    /// A connect was sent and is waiting on the remote to verify it
+    Connecting,
+    /// The handshake finished and data can flow
+    Connected,
+    /// Waiting for sent reliable data to be acknowledged before disconnecting with the data
+    DisconnectLater(u32),
+    /// A disconnect with the data was sent and is waiting to be acknowledged
+    Disconnecting(u32),
+}
+
 /// Represents information used to track the peer
 #[derive(Debug)]
 pub struct PeerInfo {
+    pub(crate) state: PeerState,
     pub(crate) outgoing_peer_id: OutgoingPeerID,
     pub(crate) incoming_peer_id: PeerID,
     pub(crate) connect_id: u32, // Originally was u16
@@ -29,29 +55,115 @@ pub struct PeerInfo {
 
     pub(crate) incoming_bandwidth: u32,
     pub(crate) outgoing_bandwidth: u32,
+    pub(crate) incoming_bandwidth_throttle_epoch: Option<Duration>,
+    pub(crate) outgoing_bandwidth_throttle_epoch: Option<Duration>,
+    /// Bytes received from the peer since the last bandwidth throttle
+    pub(crate) incoming_data_total: u32,
+    /// Bytes sent to the peer since the last bandwidth throttle
+    pub(crate) outgoing_data_total: u32,
 
     pub(crate) packet_throttle_interval: u32,
     pub(crate) packet_throttle_acceleration: u32,
     pub(crate) packet_throttle_deceleration: u32,
-
-    pub(crate) _mtu: u32,
-    pub(crate) _window_size: u32,
-
-    pub(crate) _event_data: u32,
+    /// Out of PEER_PACKET_THROTTLE_SCALE, how many unreliable packets get sent
+    pub(crate) packet_throttle: u32,
+    pub(crate) packet_throttle_limit: u32,
+    pub(crate) packet_throttle_counter: u32,
+    pub(crate) packet_throttle_epoch: Option<Duration>,
+
+    pub(crate) mtu: u32,
+    pub(crate) window_size: u32,
+    /// Reliable data sent but not yet acknowledged
+    pub(crate) reliable_data_in_transit: u32,
+    /// Reliable commands held back until the window has room for them
+    pub(crate) outgoing_reliable_commands: VecDeque<Command>,
+
+    /// The data the remote sent with its connect, or we sent with ours
+    pub(crate) connect_data: u32,
 
     pub(crate) outgoing_reliable_sequence_number: u16,
     pub(crate) incoming_reliable_sequence_number: u16,
+
+    pub(crate) outgoing_unsequenced_group: u16,
+    pub(crate) incoming_unsequenced_group: u16,
+    /// Bitset of the unsequenced groups received within the current window
+    pub(crate) unsequenced_window: [u32; PEER_UNSEQUENCED_WINDOW_SIZE / 32],
+
     pub(crate) sender: Sender<HostSendEvent>,
 
     pub(crate) last_msg_time: Instant,
+    /// Smoothed round trip time, only an estimate until the first sample
     pub(crate) round_trip_time: Duration,
     pub(crate) round_trip_time_variance: Duration,
+    pub(crate) round_trip_time_sampled: bool,
+    /// Lowest round trip time and highest variance within the current throttle epoch
+    pub(crate) lowest_round_trip_time: Duration,
+    pub(crate) highest_round_trip_time_variance: Duration,
+    /// Lowest round trip time and highest variance of the previous throttle epoch
+    pub(crate) last_round_trip_time: Duration,
+    pub(crate) last_round_trip_time_variance: Duration,
+    /// When the oldest command still unacknowledged after timing out was sent
+    pub(crate) earliest_timeout: Option<Duration>,
+
+    /// Mean fraction of reliable commands lost, out of PEER_PACKET_LOSS_SCALE
+    pub(crate) packet_loss: u32,
+    pub(crate) packet_loss_variance: u32,
+    pub(crate) packet_loss_epoch: Option<Duration>,
+    /// Reliable commands sent and lost within the current loss epoch, including retransmissions
+    pub(crate) packets_sent: u32,
+    pub(crate) packets_lost: u32,
+
+    /// Traffic counters, the rest of the stats are filled in by `PeerInfo::stats`
+    pub(crate) stats: PeerStats,
+    /// Publishes the stats to the peer's handles
+    pub(crate) stats_sender: watch::Sender<PeerStats>,
+}
+
+/// The parameters a connection was established with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectInfo {
+    /// The data sent along with the connect, such as a protocol version
+    pub data: u32,
+    /// The number of channels both sides agreed on
+    pub channel_count: usize,
+    /// The negotiated maximum datagram size
+    pub mtu: u32,
+    /// How much reliable data may be in transit to the remote
+    pub window_size: u32,
+    /// The remote's incoming bandwidth in bytes per second, 0 if unlimited
+    pub incoming_bandwidth: u32,
+    /// The remote's outgoing bandwidth in bytes per second, 0 if unlimited
+    pub outgoing_bandwidth: u32,
+}
+
+/// A snapshot of a peer's connection quality and traffic
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PeerStats {
+    /// Smoothed round trip time
+    pub round_trip_time: Duration,
+    pub round_trip_time_variance: Duration,
+    /// Mean fraction of reliable commands lost, from 0 to 1
+    pub packet_loss: f32,
+    pub packet_loss_variance: f32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Commands sent, including retransmissions
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Reliable commands resent after their acknowledgement timed out
+    pub retransmits: u64,
+    /// Out of PEER_PACKET_THROTTLE_SCALE, how many unreliable packets get sent
+    pub packet_throttle: u32,
+    /// Reliable data sent but not yet acknowledged
+    pub reliable_data_in_transit: u32,
 }
 
 /// A presentation of a peer
 pub struct Peer {
     pub(crate) id: PeerID,
     pub(crate) address: SocketAddr,
+    pub(crate) connect_info: ConnectInfo,
+    pub(crate) stats: watch::Receiver<PeerStats>,
 
     pub(crate) out_channel: tokio::sync::mpsc::Sender<HostRecvEvent>,
     pub(crate) in_channel: tokio::sync::mpsc::Receiver<HostSendEvent>,
@@ -62,6 +174,7 @@ impl std::fmt::Debug for Peer {
         f.debug_struct("Peer")
             .field("id", &self.id)
             .field("address", &self.address)
+            .field("connect_info", &self.connect_info)
             .finish()
     }
 }
@@ -92,26 +205,66 @@ impl Peer {
     pub async fn poll(&mut self) -> PeerRecvEvent {
         let event = self.in_channel.recv().await;
         match event {
-            None => PeerRecvEvent::Disconnect,
+            None => PeerRecvEvent::Disconnect(DisconnectReason::ChannelClosed),
             Some(e) => e.event,
         }
     }
 
-    pub async fn disconnect(self) {
-        let _result = self
-            .out_channel
+    /// Disconnects gracefully, the peer is removed once the remote acknowledges `data`
+    pub async fn disconnect(self, data: u32) {
+        self.split().1.disconnect(data).await
+    }
+
+    /// Disconnects once every reliable packet already sent was acknowledged
+    pub async fn disconnect_later(self, data: u32) {
+        self.split().1.disconnect_later(data).await
+    }
+
+    /// Sends a single unreliable disconnect and forgets the peer straight away
+    pub async fn disconnect_now(self, data: u32) {
+        self.split().1.disconnect_now(data).await
+    }
+
+    /// Forgets the peer without telling the remote
+    pub async fn reset(self) {
+        self.split().1.reset().await
+    }
+
+    /// Changes how quickly the remote adapts its packet throttle to round trip changes
+    pub async fn configure_throttle(
+        &mut self,
+        interval: u32,
+        acceleration: u32,
+        deceleration: u32,
+    ) -> std::result::Result<(), ChannelError> {
+        self.out_channel
             .send(HostRecvEvent {
-                event: PeerSendEvent::Disconnect,
+                event: PeerSendEvent::ThrottleConfigure(ThrottleConfigureCommand {
+                    packet_throttle_interval: interval,
+                    packet_throttle_acceleration: acceleration,
+                    packet_throttle_deceleration: deceleration,
+                }),
                 peer_id: self.id,
                 channel_id: 0xFF,
             })
-            .await;
+            .await?;
+        Ok(())
     }
 
     pub fn get_address(&self) -> SocketAddr {
         self.address
     }
 
+    /// The connect data and parameters negotiated during the handshake
+    pub fn get_connect_info(&self) -> ConnectInfo {
+        self.connect_info
+    }
+
+    /// The peer's stats as of the last time the host sent or received for it
+    pub fn stats(&self) -> PeerStats {
+        *self.stats.borrow()
+    }
+
     pub fn split(self) -> (PeerReader, PeerWriter) {
         let reader = PeerReader {
             _id: self.id,
@@ -122,6 +275,7 @@ impl Peer {
         let writer = PeerWriter {
             id: self.id,
             address: self.address,
+            stats: self.stats,
             out_channel: self.out_channel,
         };
         (reader, writer)
@@ -142,6 +296,7 @@ pub struct PeerReader {
 pub struct PeerWriter {
     pub(crate) id: PeerID,
     pub(crate) address: SocketAddr,
+    pub(crate) stats: watch::Receiver<PeerStats>,
 
     pub(crate) out_channel: tokio::sync::mpsc::Sender<HostRecvEvent>,
 }
@@ -150,7 +305,7 @@ impl PeerReader {
     pub async fn poll(&mut self) -> PeerRecvEvent {
         let event = self.in_channel.recv().await;
         match event {
-            None => PeerRecvEvent::Disconnect,
+            None => PeerRecvEvent::Disconnect(DisconnectReason::ChannelClosed),
             Some(e) => e.event,
         }
     }
@@ -160,6 +315,11 @@ impl PeerReader {
 }
 
 impl PeerWriter {
+    /// The peer's stats as of the last time the host sent or received for it
+    pub fn stats(&self) -> PeerStats {
+        *self.stats.borrow()
+    }
+
     pub async fn send(&mut self, p: Packet) -> std::result::Result<(), ChannelError> {
         self.out_channel
             .send(HostRecvEvent {
@@ -182,23 +342,334 @@ impl PeerWriter {
         Ok(())
     }
 
-    pub async fn disconnect(self) {
+    /// Disconnects gracefully, the peer is removed once the remote acknowledges `data`
+    pub async fn disconnect(self, data: u32) {
+        self.send_disconnect(PeerSendEvent::Disconnect(data)).await
+    }
+
+    /// Disconnects once every reliable packet already sent was acknowledged
+    pub async fn disconnect_later(self, data: u32) {
+        self.send_disconnect(PeerSendEvent::DisconnectLater(data))
+            .await
+    }
+
+    /// Sends a single unreliable disconnect and forgets the peer straight away
+    pub async fn disconnect_now(self, data: u32) {
+        self.send_disconnect(PeerSendEvent::DisconnectNow(data))
+            .await
+    }
+
+    /// Forgets the peer without telling the remote
+    pub async fn reset(self) {
+        self.send_disconnect(PeerSendEvent::Reset).await
+    }
+
+    async fn send_disconnect(self, event: PeerSendEvent) {
         let _result = self
             .out_channel
             .send(HostRecvEvent {
-                event: PeerSendEvent::Disconnect,
+                event,
                 peer_id: self.id,
                 channel_id: 0xFF,
             })
             .await;
     }
 
+    /// Changes how quickly the remote adapts its packet throttle to round trip changes
+    pub async fn configure_throttle(
+        &mut self,
+        interval: u32,
+        acceleration: u32,
+        deceleration: u32,
+    ) -> std::result::Result<(), ChannelError> {
+        self.out_channel
+            .send(HostRecvEvent {
+                event: PeerSendEvent::ThrottleConfigure(ThrottleConfigureCommand {
+                    packet_throttle_interval: interval,
+                    packet_throttle_acceleration: acceleration,
+                    packet_throttle_deceleration: deceleration,
+                }),
+                peer_id: self.id,
+                channel_id: 0xFF,
+            })
+            .await?;
+        Ok(())
+    }
+
     pub fn get_address(&self) -> SocketAddr {
         self.address
     }
 }
 
 impl PeerInfo {
+    pub(crate) fn new(
+        incoming_peer_id: PeerID,
+        address: SocketAddr,
+        channel_count: usize,
+        sender: Sender<HostSendEvent>,
+    ) -> Self {
+        // Create all channels ahead of time
+        let channels = (0..channel_count as u16)
+            .map(|x| (x, Channel::default()))
+            .collect();
+
+        PeerInfo {
+            state: PeerState::Connected,
+            outgoing_peer_id: OutgoingPeerID(PROTOCOL_MAXIMUM_PEER_ID as u16),
+            incoming_peer_id,
+            connect_id: 0,
+            outgoing_session_id: 0xFF,
+            incoming_session_id: 0xFF,
+            address,
+            channels,
+            incoming_bandwidth: 0,
+            outgoing_bandwidth: 0,
+            incoming_bandwidth_throttle_epoch: None,
+            outgoing_bandwidth_throttle_epoch: None,
+            incoming_data_total: 0,
+            outgoing_data_total: 0,
+            packet_throttle_interval: PEER_PACKET_THROTTLE_INTERVAL,
+            packet_throttle_acceleration: PEER_PACKET_THROTTLE_ACCELERATION,
+            packet_throttle_deceleration: PEER_PACKET_THROTTLE_DECELERATION,
+            packet_throttle: PEER_DEFAULT_PACKET_THROTTLE,
+            packet_throttle_limit: PEER_PACKET_THROTTLE_SCALE,
+            packet_throttle_counter: 0,
+            packet_throttle_epoch: None,
+            mtu: HOST_DEFAULT_MTU as u32,
+            window_size: PROTOCOL_MAXIMUM_WINDOW_SIZE as u32,
+            reliable_data_in_transit: 0,
+            outgoing_reliable_commands: Default::default(),
+            connect_data: 0,
+            sender,
+            incoming_reliable_sequence_number: 0,
+            outgoing_reliable_sequence_number: 0,
+            outgoing_unsequenced_group: 0,
+            incoming_unsequenced_group: 0,
+            unsequenced_window: [0; PEER_UNSEQUENCED_WINDOW_SIZE / 32],
+            last_msg_time: Instant::now(),
+            round_trip_time: Duration::from_millis(PEER_DEFAULT_ROUND_TRIP_TIME),
+            round_trip_time_variance: Duration::ZERO,
+            round_trip_time_sampled: false,
+            lowest_round_trip_time: Duration::from_millis(PEER_DEFAULT_ROUND_TRIP_TIME),
+            highest_round_trip_time_variance: Duration::ZERO,
+            last_round_trip_time: Duration::from_millis(PEER_DEFAULT_ROUND_TRIP_TIME),
+            last_round_trip_time_variance: Duration::ZERO,
+            earliest_timeout: None,
+            packet_loss: 0,
+            packet_loss_variance: 0,
+            packet_loss_epoch: None,
+            packets_sent: 0,
+            packets_lost: 0,
+            stats: PeerStats::default(),
+            stats_sender: watch::channel(PeerStats::default()).0,
+        }
+    }
+
+    /// How long a reliable command waits on its acknowledgement before its first resend
+    pub(crate) fn retransmit_timeout(&self) -> Duration {
+        self.round_trip_time + 4 * self.round_trip_time_variance
+    }
+
+    /// Session id to put in datagram headers, which stays 0 until the remote assigned a peer id
+    pub(crate) fn header_session_id(&self) -> u16 {
+        if self.outgoing_peer_id.0 as usize >= PROTOCOL_MAXIMUM_PEER_ID {
+            0
+        } else {
+            self.outgoing_session_id & 3
+        }
+    }
+
+    /// Largest payload that fits in a single fragment command
+    pub(crate) fn fragment_length(&self, checksum: bool) -> usize {
+        let header_size = if checksum {
+            FRAGMENT_HEADER_SIZE + CHECKSUM_SIZE
+        } else {
+            FRAGMENT_HEADER_SIZE
+        };
+        self.mtu as usize - header_size
+    }
+
+    /// Speeds up or slows down the throttle depending on how a round trip sample
+    /// compares to the previous throttle epoch
+    pub(crate) fn update_throttle(&mut self, rtt: Duration) {
+        if self.last_round_trip_time <= self.last_round_trip_time_variance {
+            self.packet_throttle = self.packet_throttle_limit;
+        } else if rtt <= self.last_round_trip_time {
+            self.packet_throttle = (self.packet_throttle + self.packet_throttle_acceleration)
+                .min(self.packet_throttle_limit);
+        } else if rtt > self.last_round_trip_time + 2 * self.last_round_trip_time_variance {
+            self.packet_throttle = self
+                .packet_throttle
+                .saturating_sub(self.packet_throttle_deceleration);
+        }
+    }
+
+    /// Folds a round trip sample into the smoothed round trip time and its variance, the
+    /// same way `enet_protocol_handle_acknowledge` does
+    pub(crate) fn update_round_trip_time(&mut self, rtt: Duration) {
+        if self.round_trip_time_sampled {
+            let diff = rtt.abs_diff(self.round_trip_time);
+            self.round_trip_time_variance = (self.round_trip_time_variance * 3 + diff) / 4;
+            self.round_trip_time = (self.round_trip_time * 7 + rtt) / 8;
+        } else {
+            self.round_trip_time = rtt;
+            self.round_trip_time_variance = (rtt + Duration::from_millis(1)) / 2;
+            self.round_trip_time_sampled = true;
+        }
+
+        self.lowest_round_trip_time = self.lowest_round_trip_time.min(self.round_trip_time);
+        self.highest_round_trip_time_variance = self
+            .highest_round_trip_time_variance
+            .max(self.round_trip_time_variance);
+    }
+
+    /// Starts a new throttle epoch once the throttle interval has passed, which the throttle
+    /// compares the next samples against
+    pub(crate) fn update_throttle_epoch(&mut self, now: Duration) {
+        let interval = Duration::from_millis(self.packet_throttle_interval.into());
+        match self.packet_throttle_epoch {
+            Some(epoch) if now.saturating_sub(epoch) < interval => {}
+            _ => {
+                self.last_round_trip_time = self.lowest_round_trip_time;
+                self.last_round_trip_time_variance = self
+                    .highest_round_trip_time_variance
+                    .max(Duration::from_millis(2));
+                self.lowest_round_trip_time = self.round_trip_time;
+                self.highest_round_trip_time_variance = self.round_trip_time_variance;
+                self.packet_throttle_epoch = Some(now);
+            }
+        }
+    }
+
+    /// Folds the reliable commands lost since the last loss epoch into the packet loss and its
+    /// variance once the loss interval has passed, the same way
+    /// `enet_protocol_send_outgoing_commands` does
+    pub(crate) fn update_packet_loss(&mut self, now: Duration) {
+        let interval = Duration::from_millis(PEER_PACKET_LOSS_INTERVAL);
+        match self.packet_loss_epoch {
+            None => self.packet_loss_epoch = Some(now),
+            Some(epoch) if now.saturating_sub(epoch) >= interval && self.packets_sent > 0 => {
+                let loss = (self.packets_lost as u64 * PEER_PACKET_LOSS_SCALE as u64
+                    / self.packets_sent as u64) as u32;
+                // Losing more than usual means the link carries less than the throttle lets through
+                if loss > self.packet_loss + self.packet_loss_variance {
+                    self.packet_throttle = self
+                        .packet_throttle
+                        .saturating_sub(self.packet_throttle_deceleration);
+                }
+                self.packet_loss_variance =
+                    (self.packet_loss_variance * 3 + loss.abs_diff(self.packet_loss)) / 4;
+                self.packet_loss = (self.packet_loss * 7 + loss) / 8;
+                self.packet_loss_epoch = Some(now);
+                self.packets_sent = 0;
+                self.packets_lost = 0;
+            }
+            Some(_) => {}
+        }
+    }
+
+    /// Caps the throttle for the next bandwidth interval and starts counting data again
+    pub(crate) fn limit_throttle(&mut self, limit: u32) {
+        self.packet_throttle_limit = limit;
+        self.packet_throttle = self.packet_throttle.min(limit);
+        self.incoming_data_total = 0;
+        self.outgoing_data_total = 0;
+    }
+
+    /// Sizes the reliable window from the peer's incoming bandwidth and our outgoing bandwidth
+    pub(crate) fn update_window_size(&mut self, outgoing_bandwidth: u32) {
+        let bandwidth = if self.incoming_bandwidth == 0 || outgoing_bandwidth == 0 {
+            self.incoming_bandwidth.max(outgoing_bandwidth)
+        } else {
+            self.incoming_bandwidth.min(outgoing_bandwidth)
+        };
+        self.window_size = bandwidth_window_size(bandwidth);
+    }
+
+    /// Whether a reliable command of the given length fits in the throttled window
+    pub(crate) fn window_has_room(&self, length: u32) -> bool {
+        if self.reliable_data_in_transit == 0 {
+            return true;
+        }
+        let window = (self.packet_throttle * self.window_size) / PEER_PACKET_THROTTLE_SCALE;
+        self.reliable_data_in_transit + length <= window.max(self.mtu)
+    }
+
+    /// Advances the throttle counter, returning true if the next unreliable packet
+    /// should be dropped
+    pub(crate) fn throttle_drop(&mut self) -> bool {
+        self.packet_throttle_counter += PEER_PACKET_THROTTLE_COUNTER;
+        self.packet_throttle_counter %= PEER_PACKET_THROTTLE_SCALE;
+        self.packet_throttle_counter > self.packet_throttle
+    }
+
+    /// Records an unsequenced group, returning false if it was already received
+    /// or lies too far ahead of the current window
+    pub(crate) fn accept_unsequenced(&mut self, group: u16) -> bool {
+        let index = group as usize % PEER_UNSEQUENCED_WINDOW_SIZE;
+        let current = self.incoming_unsequenced_group as usize;
+
+        let mut unwrapped = group as usize;
+        if unwrapped < current {
+            unwrapped += 0x10000;
+        }
+        if unwrapped >= current + PEER_FREE_UNSEQUENCED_WINDOWS * PEER_UNSEQUENCED_WINDOW_SIZE {
+            return false;
+        }
+
+        let window_start = group.wrapping_sub(index as u16);
+        if window_start != self.incoming_unsequenced_group {
+            self.incoming_unsequenced_group = window_start;
+            self.unsequenced_window = Default::default();
+        } else if self.unsequenced_window[index / 32] & (1 << (index % 32)) != 0 {
+            return false;
+        }
+
+        self.unsequenced_window[index / 32] |= 1 << (index % 32);
+        true
+    }
+
+    /// Counts a command sent to the peer
+    pub(crate) fn count_sent(&mut self, size: usize, reliable: bool) {
+        self.outgoing_data_total = self.outgoing_data_total.saturating_add(size as u32);
+        self.stats.bytes_sent += size as u64;
+        self.stats.packets_sent += 1;
+        if reliable {
+            self.packets_sent = self.packets_sent.saturating_add(1);
+        }
+    }
+
+    /// A snapshot of the peer's stats
+    pub(crate) fn stats(&self) -> PeerStats {
+        let scale = PEER_PACKET_LOSS_SCALE as f32;
+        PeerStats {
+            round_trip_time: self.round_trip_time,
+            round_trip_time_variance: self.round_trip_time_variance,
+            packet_loss: self.packet_loss as f32 / scale,
+            packet_loss_variance: self.packet_loss_variance as f32 / scale,
+            packet_throttle: self.packet_throttle,
+            reliable_data_in_transit: self.reliable_data_in_transit,
+            ..self.stats
+        }
+    }
+
+    /// Shares the latest stats with the peer's handles
+    pub(crate) fn publish_stats(&self) {
+        self.stats_sender.send_replace(self.stats());
+    }
+
+    /// The parameters to hand out with the peer once connected
+    pub(crate) fn connect_info(&self) -> ConnectInfo {
+        ConnectInfo {
+            data: self.connect_data,
+            channel_count: self.channels.len(),
+            mtu: self.mtu,
+            window_size: self.window_size,
+            incoming_bandwidth: self.incoming_bandwidth,
+            outgoing_bandwidth: self.outgoing_bandwidth,
+        }
+    }
+
     pub fn get_channel(&self, id: ChannelID) -> Result<&Channel> {
         let channel = self
             .channels
@@ -216,6 +687,16 @@ impl PeerInfo {
     }
 }
 
+/// The reliable window for a bandwidth in bytes per second, where 0 is unlimited
+pub(crate) fn bandwidth_window_size(bandwidth: u32) -> u32 {
+    let window_size = if bandwidth == 0 {
+        PROTOCOL_MAXIMUM_WINDOW_SIZE
+    } else {
+        (bandwidth as usize / PEER_WINDOW_SIZE_SCALE) * PROTOCOL_MINIMUM_WINDOW_SIZE
+    };
+    window_size.clamp(PROTOCOL_MINIMUM_WINDOW_SIZE, PROTOCOL_MAXIMUM_WINDOW_SIZE) as u32
+}
+
 /// A packet to send to a peer
 #[derive(Debug, Clone)]
 pub struct Packet {
@@ -230,12 +711,30 @@ pub enum PeerSendEvent {
     Send(Packet),
     Broadcast(Packet),
     Ping,
-    Disconnect,
+    ThrottleConfigure(ThrottleConfigureCommand),
+    Disconnect(u32),
+    DisconnectLater(u32),
+    DisconnectNow(u32),
+    Reset,
 }
 
 /// An event a peer receives to the host
 #[derive(Debug)]
 pub enum PeerRecvEvent {
     Recv(Packet),
-    Disconnect,
+    /// The connection closed
+    Disconnect(DisconnectReason),
+}
+
+/// Why a peer was disconnected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The remote disconnected with the given data
+    Remote(u32),
+    /// The peer was disconnected locally with the given data
+    Local(u32),
+    /// The remote stopped acknowledging reliable commands
+    Timeout,
+    /// The host dropped the channel to the peer
+    ChannelClosed,
 }
@@ -1,6 +1,10 @@
 use std::{net::SocketAddr, time::Duration};
 
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{Error as DeError, SeqAccess, Visitor},
+    ser::{Error as SerError, SerializeStruct},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 
 use crate::{error::ENetError, net::time::PacketTime, peer::PeerID};
 
@@ -61,6 +65,29 @@ impl PacketFlags {
         };
         flags
     }
+
+    /// Packets larger than the peer's fragment length are sent as unreliable fragments, which
+    /// are sequenced like any unreliable packet, as in ENet
+    pub fn unsequenced() -> Self {
+        PacketFlags {
+            unsequenced: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl ProtocolCommand {
+    /// Length of the packet data carried by the command
+    pub fn data_length(&self) -> usize {
+        match self {
+            ProtocolCommand::SendReliable(c) => c.data.len(),
+            ProtocolCommand::SendUnreliable(c) => c.data.len(),
+            ProtocolCommand::SendFragment(c) => c.data.len(),
+            ProtocolCommand::SendUnsequenced(c) => c.data.len(),
+            ProtocolCommand::SendUnreliableFragment(c) => c.data.len(),
+            _ => 0,
+        }
+    }
 }
 
 /// Client header information before commands in udp packet
@@ -202,10 +229,10 @@ pub struct SendUnsequencedCommand {
 
 /// Command send a fragmented packet
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
-#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct SendFragmentCommand {
     pub start_sequence_number: u16,
-    pub data_length: u16,
+    // pub data_length: u16,
     pub fragment_count: u32,
     pub fragment_number: u32,
     pub total_length: u32,
@@ -215,10 +242,10 @@ pub struct SendFragmentCommand {
 
 /// Command send a unreliable fragmented packet
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
-#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct SendUnreliableFragmentCommand {
     pub start_sequence_number: u16,
-    pub data_length: u16,
+    // pub data_length: u16,
     pub fragment_count: u32,
     pub fragment_number: u32,
     pub total_length: u32,
@@ -226,6 +253,83 @@ pub struct SendUnreliableFragmentCommand {
     pub data: Vec<u8>,
 }
 
+/// Fragment payload written without a length prefix
+struct FragmentData<'a>(&'a [u8]);
+
+impl Serialize for FragmentData<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+/// Fragments carry their data length ahead of the other fields, so the
+/// payload cannot be encoded as a regular length prefixed sequence
+macro_rules! impl_fragment_serde {
+    ($p: ident) => {
+        impl Serialize for $p {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let data_length: u16 = self.data.len().try_into().map_err(SerError::custom)?;
+
+                let mut state = serializer.serialize_struct(stringify!($p), 7)?;
+                state.serialize_field("start_sequence_number", &self.start_sequence_number)?;
+                state.serialize_field("data_length", &data_length)?;
+                state.serialize_field("fragment_count", &self.fragment_count)?;
+                state.serialize_field("fragment_number", &self.fragment_number)?;
+                state.serialize_field("total_length", &self.total_length)?;
+                state.serialize_field("fragment_offset", &self.fragment_offset)?;
+                state.serialize_field("data", &FragmentData(&self.data))?;
+                state.end()
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $p {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct FragmentVisitor;
+
+                impl<'de> Visitor<'de> for FragmentVisitor {
+                    type Value = $p;
+
+                    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                        f.write_str(stringify!($p))
+                    }
+
+                    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<$p, A::Error> {
+                        let start_sequence_number = next_field(&mut seq)?;
+                        let data_length: u16 = next_field(&mut seq)?;
+                        let fragment_count = next_field(&mut seq)?;
+                        let fragment_number = next_field(&mut seq)?;
+                        let total_length = next_field(&mut seq)?;
+                        let fragment_offset = next_field(&mut seq)?;
+                        let data = (0..data_length)
+                            .map(|_| next_field(&mut seq))
+                            .collect::<Result<_, _>>()?;
+
+                        Ok($p {
+                            start_sequence_number,
+                            fragment_count,
+                            fragment_number,
+                            total_length,
+                            fragment_offset,
+                            data,
+                        })
+                    }
+                }
+
+                // Six header fields followed by up to u16::MAX data bytes
+                deserializer.deserialize_tuple(6 + u16::MAX as usize, FragmentVisitor)
+            }
+        }
+    };
+}
+
+fn next_field<'de, T: Deserialize<'de>, A: SeqAccess<'de>>(seq: &mut A) -> Result<T, A::Error> {
+    seq.next_element()?
+        .ok_or_else(|| DeError::custom("missing fragment field"))
+}
+
+impl_fragment_serde!(SendFragmentCommand);
+impl_fragment_serde!(SendUnreliableFragmentCommand);
+
 macro_rules! impl_packet_conv {
     ($p: ty, $wrap: path) => {
         impl From<$p> for ProtocolCommand {
@@ -1,4 +1,1160 @@
-use crate::host::Host;
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::net::UdpSocket;
+
+use crate::{
+    channel::{Channel, ReliableWindow},
+    consts::PROTOCOL_MAXIMUM_FRAGMENT_COUNT,
+    error::{ConnectError, ENetError},
+    host::{
+        accept::{ConnectDecision, ConnectRequest},
+        config::HostConfig,
+        hostevents::HostPollEvent,
+        next_session_id, Host,
+    },
+    net::{
+        socket::{ENetSocket, Socket},
+        time::PacketTime,
+    },
+    peer::{DisconnectReason, Packet, Peer, PeerID, PeerInfo, PeerRecvEvent},
+    protocol::{
+        AcknowledgeCommand, Command, CommandInfo, ConnectCommand, DisconnectCommand, PacketFlags,
+        ProtocolCommand, SendReliableCommand, SendUnreliableCommand, SendUnreliableFragmentCommand,
+        VerifyConnectCommand,
+    },
+};
+
+fn test_peer() -> PeerInfo {
+    let (sender, _) = tokio::sync::mpsc::channel(1);
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9000);
+    PeerInfo::new(PeerID(0), addr, 1, sender)
+}
+
+fn test_connect() -> ConnectCommand {
+    ConnectCommand {
+        outgoing_peer_id: 0,
+        incoming_session_id: 0xFF,
+        outgoing_session_id: 0xFF,
+        mtu: 1400,
+        window_size: 32768,
+        channel_count: 1,
+        incoming_bandwidth: 0,
+        outgoing_bandwidth: 0,
+        packet_throttle_interval: 5000,
+        packet_throttle_acceleration: 2,
+        packet_throttle_deceleration: 2,
+        connect_id: 1,
+        data: 0,
+    }
+}
+
+const POLL: Duration = Duration::from_millis(100);
+
+/// A host on a local port, along with a raw socket standing in for a remote ENet host
+async fn host_and_remote(config: HostConfig) -> (Host, ENetSocket) {
+    let host = Host::create_from_address(config, "127.0.0.1:0")
+        .await
+        .unwrap();
+    let remote = ENetSocket::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    (host, remote)
+}
+
+/// A connect from the remote, sent before the host gave it a peer id
+fn connect_command(host: &Host, connect: ConnectCommand) -> Command {
+    Command {
+        info: CommandInfo {
+            addr: host.socket.socket.local_addr().unwrap(),
+            flags: PacketFlags::reliable(),
+            internal_peer_id: PeerID(0),
+            peer_id: PeerID(0xFFF),
+            channel_id: 0xFF,
+            session_id: 0,
+            reliable_sequence_number: 1,
+            sent_time: Duration::ZERO,
+        },
+        command: connect.into(),
+    }
+}
+
+/// Header of an unreliable command from the remote, on the peer id and session its verify
+/// gave it
+fn remote_info(host: &Host, verify: &VerifyConnectCommand) -> CommandInfo {
+    CommandInfo {
+        flags: PacketFlags::default(),
+        peer_id: PeerID(verify.outgoing_peer_id),
+        session_id: verify.outgoing_session_id.into(),
+        reliable_sequence_number: 0,
+        ..connect_command(host, test_connect()).info
+    }
+}
+
+/// The remote's acknowledgement of a reliable command the host sent it
+fn remote_ack(
+    host: &Host,
+    verify: &VerifyConnectCommand,
+    channel_id: u8,
+    reliable_sequence_number: u16,
+    sent_time: Duration,
+) -> Command {
+    Command {
+        info: CommandInfo {
+            channel_id,
+            ..remote_info(host, verify)
+        },
+        command: AcknowledgeCommand {
+            received_reliable_sequence_number: reliable_sequence_number,
+            received_sent_time: PacketTime::from_duration(&sent_time),
+        }
+        .into(),
+    }
+}
+
+/// Receives on the remote until the host's verify arrives
+async fn recv_verify(remote: &mut ENetSocket) -> (CommandInfo, VerifyConnectCommand) {
+    loop {
+        let command = tokio::time::timeout(Duration::from_secs(1), remote.recv())
+            .await
+            .expect("verify was not sent")
+            .unwrap();
+        if let ProtocolCommand::VerifyConnect(verify) = command.command {
+            return (command.info, verify);
+        }
+    }
+}
+
+/// A host with a peer connected from the remote
+async fn connected_pair(config: HostConfig) -> (Host, ENetSocket, Peer, VerifyConnectCommand) {
+    let (mut host, mut remote) = host_and_remote(config).await;
+    remote
+        .send(&connect_command(&host, test_connect()))
+        .await
+        .unwrap();
+    let HostPollEvent::Connect(peer) = host.poll_for_event(POLL).await.unwrap() else {
+        panic!("peer did not connect");
+    };
+    let (_, verify) = recv_verify(&mut remote).await;
+    (host, remote, peer, verify)
+}
+
+#[test]
+fn unsequenced_window_drops_duplicates() {
+    let mut peer = test_peer();
+
+    assert!(peer.accept_unsequenced(1));
+    assert!(peer.accept_unsequenced(3));
+    assert!(peer.accept_unsequenced(2));
+    assert!(!peer.accept_unsequenced(1));
+    assert!(!peer.accept_unsequenced(3));
+
+    // Moving to the next window forgets the previous one
+    assert!(peer.accept_unsequenced(1024));
+    assert!(!peer.accept_unsequenced(1024));
+
+    // Groups too far ahead are dropped
+    assert!(!peer.accept_unsequenced(1024 + 32 * 1024));
+}
 
 #[test]
-fn test() {}
+fn reliable_window_holds_early_commands() {
+    let mut channel = Channel {
+        incoming_reliable_sequence_number: 10,
+        ..Default::default()
+    };
+
+    assert_eq!(channel.reliable_window(11), ReliableWindow::Accept);
+    assert_eq!(channel.reliable_window(500), ReliableWindow::Accept);
+    assert_eq!(channel.reliable_window(10), ReliableWindow::Discard);
+    assert_eq!(channel.reliable_window(9), ReliableWindow::Discard);
+    assert_eq!(channel.reliable_window(0x7000), ReliableWindow::Ahead);
+
+    // Wraps around the end of the sequence space
+    channel.incoming_reliable_sequence_number = 0xFFF0;
+    assert_eq!(channel.reliable_window(2), ReliableWindow::Accept);
+    assert_eq!(channel.reliable_window(0xFFEF), ReliableWindow::Discard);
+}
+
+#[test]
+fn unreliable_sequence_drops_stale_commands() {
+    let mut channel = Channel {
+        incoming_reliable_sequence_number: 4,
+        ..Default::default()
+    };
+    let unreliable = |reliable_sequence_number| Command {
+        info: CommandInfo {
+            addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9000),
+            flags: PacketFlags::default(),
+            internal_peer_id: PeerID(0),
+            peer_id: PeerID(0),
+            channel_id: 0,
+            session_id: 0,
+            reliable_sequence_number,
+            sent_time: Duration::ZERO,
+        },
+        command: ProtocolCommand::None,
+    };
+
+    assert!(channel.accept_unreliable(&unreliable(4), 2));
+    // A lost command does not hold the sequence back
+    assert!(channel.accept_unreliable(&unreliable(4), 5));
+    assert!(!channel.accept_unreliable(&unreliable(4), 5));
+    assert!(!channel.accept_unreliable(&unreliable(4), 3));
+    assert!(!channel.accept_unreliable(&unreliable(3), 9));
+
+    // Held once until the reliable command it was sent after is delivered
+    assert!(!channel.accept_unreliable(&unreliable(5), 1));
+    assert!(!channel.accept_unreliable(&unreliable(5), 1));
+    assert_eq!(channel.incoming_unreliable_sequence_number, 5);
+    assert_eq!(channel.incoming_unreliable_commands.len(), 1);
+}
+
+#[test]
+fn throttle_follows_round_trip_time() {
+    let mut peer = test_peer();
+    peer.update_throttle_epoch(Duration::ZERO);
+    peer.last_round_trip_time = Duration::from_millis(100);
+    peer.last_round_trip_time_variance = Duration::from_millis(10);
+
+    peer.update_throttle(Duration::from_millis(300));
+    assert_eq!(peer.packet_throttle, 30);
+
+    // Halfway throttled, roughly half the unreliable packets are dropped
+    peer.packet_throttle = 16;
+    let dropped = (0..32).filter(|_| peer.throttle_drop()).count();
+    assert_eq!(dropped, 15);
+
+    peer.update_throttle(Duration::from_millis(50));
+    assert_eq!(peer.packet_throttle, 18);
+}
+
+#[test]
+fn round_trip_time_is_smoothed() {
+    let ms = Duration::from_millis;
+    let mut peer = test_peer();
+
+    peer.update_round_trip_time(ms(100));
+    assert_eq!(
+        (peer.round_trip_time, peer.round_trip_time_variance),
+        (ms(100), Duration::from_micros(50_500))
+    );
+
+    peer.update_round_trip_time(ms(20));
+    assert_eq!(peer.round_trip_time, ms(90));
+    assert_eq!(peer.round_trip_time_variance, Duration::from_micros(57_875));
+    assert_eq!(peer.lowest_round_trip_time, ms(90));
+
+    // A new epoch compares the throttle against the extremes of the last one
+    peer.update_throttle_epoch(Duration::ZERO);
+    assert_eq!(peer.last_round_trip_time, ms(90));
+    assert_eq!(
+        peer.last_round_trip_time_variance,
+        Duration::from_micros(57_875)
+    );
+}
+
+#[test]
+fn packet_loss_is_smoothed_per_epoch() {
+    let secs = Duration::from_secs;
+    let mut peer = test_peer();
+    peer.update_packet_loss(secs(1));
+
+    // Nothing is folded in until the loss interval has passed
+    peer.packets_sent = 8;
+    peer.packets_lost = 2;
+    peer.update_packet_loss(secs(5));
+    assert_eq!(peer.packet_loss, 0);
+
+    // A quarter lost, which is more than usual and slows the throttle
+    peer.update_packet_loss(secs(11));
+    assert_eq!(peer.packet_loss, 16384 / 8);
+    assert_eq!(peer.packet_loss_variance, 16384 / 4);
+    assert_eq!(peer.packet_throttle, 30);
+    assert_eq!((peer.packets_sent, peer.packets_lost), (0, 0));
+
+    // An epoch without loss eases the mean back down
+    peer.packets_sent = 10;
+    peer.update_packet_loss(secs(21));
+    assert_eq!(peer.packet_loss, 2048 * 7 / 8);
+    assert_eq!(peer.packet_throttle, 30);
+    let stats = peer.stats();
+    assert_eq!(stats.packet_loss, 1792.0 / 65536.0);
+}
+
+#[test]
+fn window_fills_with_reliable_data() {
+    let mut peer = test_peer();
+    peer.incoming_bandwidth = 64 * 1024;
+    peer.update_window_size(0);
+    assert_eq!(peer.window_size, 4096);
+
+    assert!(peer.window_has_room(5000));
+    peer.reliable_data_in_transit = 4000;
+    assert!(!peer.window_has_room(1000));
+
+    // A throttled window still lets one mtu through
+    peer.packet_throttle = 0;
+    peer.reliable_data_in_transit = 400;
+    assert!(peer.window_has_room(1000));
+}
+
+#[tokio::test]
+async fn connect_mtu_is_negotiated() {
+    let mut config = HostConfig::new(10).unwrap();
+    config.mtu = 1200;
+    let mut host = Host::create_from_address(config, "127.0.0.1:0")
+        .await
+        .unwrap();
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9000);
+
+    let mut connect = test_connect();
+    connect.mtu = 100;
+    let (_, verify) = host.handle_connect(addr, &connect).unwrap();
+    assert_eq!(verify.mtu, 576);
+
+    // Capped by the host mtu rather than the protocol maximum
+    connect.mtu = 100_000;
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9001);
+    let (peer, verify) = host.handle_connect(addr, &connect).unwrap();
+    assert_eq!(verify.mtu, 1200);
+    assert_eq!(host.get_peer(peer.id).unwrap().mtu, 1200);
+}
+
+#[test]
+fn sessions_rotate_on_reconnect() {
+    // A fresh slot starts at session 0 and each reconnect moves to the next one
+    assert_eq!(next_session_id(0xFF, 0xFF), 0);
+    assert_eq!(next_session_id(0xFF, 0), 1);
+    assert_eq!(next_session_id(0xFF, 3), 0);
+
+    // A requested session is skipped past when it is the one last used
+    assert_eq!(next_session_id(1, 3), 2);
+    assert_eq!(next_session_id(2, 3), 0);
+}
+
+#[tokio::test]
+async fn peer_slots_are_reused() {
+    assert!(HostConfig::new(0x1000).is_err());
+
+    let config = HostConfig::new(2).unwrap();
+    let mut host = Host::create_from_address(config, "127.0.0.1:0")
+        .await
+        .unwrap();
+    let addr = |port| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+
+    let (mut first, verify) = host.handle_connect(addr(9000), &test_connect()).unwrap();
+    let (second, _) = host.handle_connect(addr(9001), &test_connect()).unwrap();
+    assert_eq!((first.id, second.id), (PeerID(0), PeerID(1)));
+    assert!(matches!(
+        host.handle_connect(addr(9002), &test_connect()),
+        Err(ENetError::NoFreePeers)
+    ));
+
+    // Each disconnect frees the slot, and the next peer in it moves on to a new session
+    let mut session_id = verify.outgoing_session_id;
+    for port in 9002..9100 {
+        first.disconnect_now(0).await;
+        let event = host.poll_for_event(POLL).await.unwrap();
+        assert!(matches!(event, HostPollEvent::NoEvent));
+        assert!(!host.peers.contains_key(&PeerID(0)));
+
+        let (peer, verify) = host.handle_connect(addr(port), &test_connect()).unwrap();
+        assert_eq!(peer.id, PeerID(0));
+        assert_ne!(verify.outgoing_session_id, session_id);
+        session_id = verify.outgoing_session_id;
+        first = peer;
+    }
+}
+
+#[tokio::test]
+async fn repeated_connects_resend_verify() {
+    let (mut host, mut remote) = host_and_remote(HostConfig::new(10).unwrap()).await;
+    let connect = connect_command(&host, test_connect());
+    remote.send(&connect).await.unwrap();
+    let event = host.poll_for_event(POLL).await.unwrap();
+    assert!(matches!(event, HostPollEvent::Connect(_)));
+    let (first, _) = recv_verify(&mut remote).await;
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    remote.send(&connect).await.unwrap();
+    let event = host.poll_for_event(POLL).await.unwrap();
+    assert!(matches!(event, HostPollEvent::NoEvent));
+    assert_eq!(host.peers.len(), 1);
+
+    // The same verify goes out again with a fresh sent time, and is not taken as lost
+    let (second, _) = recv_verify(&mut remote).await;
+    assert_eq!(
+        second.reliable_sequence_number,
+        first.reliable_sequence_number
+    );
+    assert!(second.sent_time >= first.sent_time + Duration::from_millis(30));
+    host.poll_for_event(POLL).await.unwrap();
+    assert_eq!(host.stats().retransmits, 0);
+}
+
+#[tokio::test]
+async fn connect_fields_are_validated() {
+    let mut config = HostConfig::new(10).unwrap();
+    config.channel_limit = Some(2);
+    let mut host = Host::create_from_address(config, "127.0.0.1:0")
+        .await
+        .unwrap();
+    let addr = |port| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+
+    for channel_count in [0, 256] {
+        let mut connect = test_connect();
+        connect.channel_count = channel_count;
+        assert!(matches!(
+            host.handle_connect(addr(9000), &connect),
+            Err(ENetError::InvalidConnect(ConnectError::ChannelCount(_)))
+        ));
+    }
+    let mut connect = test_connect();
+    connect.outgoing_peer_id = 0xFFF;
+    assert!(matches!(
+        host.handle_connect(addr(9000), &connect),
+        Err(ENetError::InvalidConnect(ConnectError::PeerId(0xFFF)))
+    ));
+    assert!(host.peers.is_empty());
+
+    let mut connect = test_connect();
+    connect.channel_count = 10;
+    connect.window_size = 1;
+    connect.packet_throttle_acceleration = u32::MAX;
+    connect.data = 7;
+    let (peer, verify) = host.handle_connect(addr(9000), &connect).unwrap();
+    assert_eq!(verify.channel_count, 2);
+    let connect_info = peer.get_connect_info();
+    assert_eq!((connect_info.data, connect_info.channel_count), (7, 2));
+    assert_eq!(connect_info.mtu, 1400);
+    assert_eq!(verify.window_size, 4096);
+    assert_eq!(verify.packet_throttle_acceleration, u32::MAX);
+
+    let peer = host.get_peer(peer.id).unwrap();
+    assert_eq!(peer.channels.len(), 2);
+    assert_eq!(peer.packet_throttle_acceleration, 32);
+}
+
+#[tokio::test]
+async fn disconnects_carry_data() {
+    let (mut host, mut remote, _, verify) = connected_pair(HostConfig::new(10).unwrap()).await;
+
+    // The remote disconnecting passes its data to the host
+    remote
+        .send(&Command {
+            info: CommandInfo {
+                flags: PacketFlags::reliable(),
+                reliable_sequence_number: 2,
+                ..remote_info(&host, &verify)
+            },
+            command: DisconnectCommand { data: 9 }.into(),
+        })
+        .await
+        .unwrap();
+    let event = host.poll_for_event(POLL).await.unwrap();
+    assert!(matches!(
+        event,
+        HostPollEvent::Disconnect(PeerID(0), DisconnectReason::Remote(9))
+    ));
+    assert!(host.peers.is_empty());
+
+    // Disconnecting now sends a single unsequenced notice and forgets the peer
+    let mut connect = test_connect();
+    connect.connect_id = 2;
+    remote.send(&connect_command(&host, connect)).await.unwrap();
+    let HostPollEvent::Connect(peer) = host.poll_for_event(POLL).await.unwrap() else {
+        panic!("peer did not reconnect");
+    };
+    let (verify_info, _) = recv_verify(&mut remote).await;
+    let (mut reader, writer) = peer.split();
+    writer.disconnect_now(7).await;
+    let event = host.poll_for_event(POLL).await.unwrap();
+    assert!(matches!(event, HostPollEvent::NoEvent));
+    assert!(host.peers.is_empty());
+    assert!(matches!(
+        reader.poll().await,
+        PeerRecvEvent::Disconnect(DisconnectReason::Local(7))
+    ));
+
+    let disconnect = loop {
+        let command = tokio::time::timeout(Duration::from_secs(1), remote.recv())
+            .await
+            .expect("disconnect was not sent")
+            .unwrap();
+        if let ProtocolCommand::Disconnect(d) = command.command {
+            // Sequenced on the system channel after the verify, without an unsequenced group
+            assert!(command.info.flags.unsequenced);
+            assert_eq!(command.info.channel_id, 0xFF);
+            assert_eq!(
+                command.info.reliable_sequence_number,
+                verify_info.reliable_sequence_number + 1
+            );
+            break d;
+        }
+    };
+    assert_eq!(disconnect.data, 7);
+}
+
+#[tokio::test]
+async fn connect_policy_rejects_and_limits_channels() {
+    let (mut host, mut remote) = host_and_remote(HostConfig::new(1).unwrap()).await;
+    host.set_connect_policy(|request: &ConnectRequest| match request.data {
+        13 => ConnectDecision::Reject(99),
+        _ => ConnectDecision::AcceptChannels(1),
+    });
+
+    let rejected = |connect_id| ConnectCommand {
+        outgoing_peer_id: 5,
+        connect_id,
+        data: 13,
+        ..test_connect()
+    };
+    async fn recv_rejection(remote: &mut ENetSocket) {
+        let command = remote.recv().await.unwrap();
+        assert!(command.info.flags.unsequenced);
+        assert_eq!(command.info.peer_id, PeerID(5));
+        assert!(matches!(
+            command.command,
+            ProtocolCommand::Disconnect(DisconnectCommand { data: 99 })
+        ));
+    }
+
+    remote
+        .send(&connect_command(&host, rejected(1)))
+        .await
+        .unwrap();
+    let event = host.poll_for_event(POLL).await.unwrap();
+    assert!(matches!(event, HostPollEvent::NoEvent));
+    assert!(host.peers.is_empty());
+    recv_rejection(&mut remote).await;
+
+    let mut connect = test_connect();
+    connect.channel_count = 4;
+    connect.connect_id = 2;
+    remote.send(&connect_command(&host, connect)).await.unwrap();
+    let HostPollEvent::Connect(peer) = host.poll_for_event(POLL).await.unwrap() else {
+        panic!("connect was not accepted");
+    };
+    assert_eq!(peer.get_connect_info().channel_count, 1);
+    // The rejection never took the slot, so the session did not move on
+    let (_, verify) = recv_verify(&mut remote).await;
+    assert_eq!(verify.outgoing_session_id, 0);
+
+    // Still answered once the host is full
+    remote
+        .send(&connect_command(&host, rejected(3)))
+        .await
+        .unwrap();
+    host.poll_for_event(POLL).await.unwrap();
+    assert_eq!(host.peers.len(), 1);
+    recv_rejection(&mut remote).await;
+}
+
+#[tokio::test]
+async fn host_stats_count_connects() {
+    let (mut host, mut remote) = host_and_remote(HostConfig::new(10).unwrap()).await;
+    host.set_connect_policy(|request: &ConnectRequest| match request.data {
+        13 => ConnectDecision::Reject(0),
+        _ => ConnectDecision::Accept,
+    });
+
+    for (connect_id, data) in [(1, 13), (2, 0)] {
+        let mut connect = test_connect();
+        connect.connect_id = connect_id;
+        connect.data = data;
+        remote.send(&connect_command(&host, connect)).await.unwrap();
+        host.poll_for_event(POLL).await.unwrap();
+    }
+
+    // The disconnect refusing the first connect and the verify accepting the second
+    remote.recv().await.unwrap();
+    remote.recv().await.unwrap();
+
+    let stats = host.stats();
+    assert_eq!((stats.connects_accepted, stats.connects_refused), (1, 1));
+    assert_eq!(stats.socket.datagrams_received, 2);
+    assert_eq!(stats.socket.commands_received.connect, 2);
+    assert_eq!(stats.socket.commands_sent.disconnect, 1);
+    assert_eq!(stats.socket.commands_sent.verify_connect, 1);
+    assert_eq!(stats.socket.datagrams_sent, 2);
+    assert_eq!(stats.socket.bytes_sent, remote.stats().bytes_received);
+
+    host.reset_stats();
+    assert_eq!(host.stats(), Default::default());
+}
+
+#[tokio::test]
+async fn connect_cookies_defer_peers() {
+    let mut config = HostConfig::new(10).unwrap();
+    config.connect_cookies = true;
+    let (mut host, mut remote) = host_and_remote(config).await;
+
+    // A flood of connects that are never acknowledged takes no peers and locks nobody out
+    for connect_id in 0..100 {
+        let mut connect = test_connect();
+        connect.connect_id = connect_id;
+        let mut spoofer = ENetSocket::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        spoofer
+            .send(&connect_command(&host, connect))
+            .await
+            .unwrap();
+        host.poll_for_event(POLL).await.unwrap();
+    }
+    assert!(host.peers.is_empty());
+
+    remote
+        .send(&connect_command(&host, test_connect()))
+        .await
+        .unwrap();
+    let event = host.poll_for_event(POLL).await.unwrap();
+    assert!(matches!(event, HostPollEvent::NoEvent));
+    assert!(host.peers.is_empty());
+
+    // The cookie takes up the verify's reliable sequence number and sent time
+    let (verify_info, verify) = recv_verify(&mut remote).await;
+    let ack = |reliable_sequence_number| {
+        remote_ack(
+            &host,
+            &verify,
+            0xFF,
+            reliable_sequence_number,
+            verify_info.sent_time,
+        )
+    };
+    let (ack, bad_cookie) = (
+        ack(verify_info.reliable_sequence_number),
+        ack(verify_info.reliable_sequence_number ^ 1),
+    );
+    remote.send(&bad_cookie).await.unwrap();
+    let event = host.poll_for_event(POLL).await.unwrap();
+    assert!(matches!(event, HostPollEvent::NoEvent));
+    assert!(host.peers.is_empty());
+
+    remote.send(&ack).await.unwrap();
+    let event = host.poll_for_event(POLL).await.unwrap();
+    let HostPollEvent::Connect(peer) = event else {
+        panic!("cookie was not accepted");
+    };
+    assert_eq!(peer.id, PeerID(verify.outgoing_peer_id));
+    assert_eq!(host.stats().connects_accepted, 1);
+}
+
+#[tokio::test]
+async fn retransmits_back_off_until_timeout() {
+    let mut config = HostConfig::new(10).unwrap();
+    config.timeout_limit = 4;
+    config.timeout_minimum = Duration::from_millis(200);
+    let (mut host, mut remote, mut peer, _) = connected_pair(config).await;
+
+    let info = host.peers.get_mut(&peer.id).unwrap();
+    info.round_trip_time = Duration::from_millis(20);
+    info.round_trip_time_variance = Duration::ZERO;
+    let packet = Packet {
+        data: vec![1, 2, 3],
+        channel: 0,
+        flags: PacketFlags::reliable(),
+    };
+    peer.send(packet).await.unwrap();
+
+    // Sent at 0, then resent after timeouts of 20, 40 and 80ms, the last of which reached the
+    // limit of 4 times the first, so the peer times out once 200ms passed
+    let start = tokio::time::Instant::now();
+    let host_events = async {
+        loop {
+            match host.poll_for_event(Duration::from_millis(5)).await.unwrap() {
+                HostPollEvent::Disconnect(id, reason) => break (id, reason, start.elapsed()),
+                _ => assert!(start.elapsed() < Duration::from_secs(2)),
+            }
+        }
+    };
+    let remote_sends = async {
+        let mut sent = Vec::new();
+        while let Ok(command) = tokio::time::timeout(POLL * 5, remote.recv()).await {
+            if let ProtocolCommand::SendReliable(_) = command.unwrap().command {
+                sent.push(start.elapsed());
+            }
+        }
+        sent
+    };
+    let ((id, reason, timed_out), sent) = tokio::join!(host_events, remote_sends);
+
+    assert_eq!((id, reason), (peer.id, DisconnectReason::Timeout));
+    assert!(timed_out >= Duration::from_millis(200));
+    assert_eq!(sent.len(), 4);
+    let gaps: Vec<_> = sent.windows(2).map(|w| w[1] - w[0]).collect();
+    assert!(gaps.windows(2).all(|w| w[1] > w[0]), "{gaps:?}");
+}
+
+#[tokio::test]
+async fn peer_stats_follow_traffic() {
+    let (mut host, mut remote, mut peer, verify) =
+        connected_pair(HostConfig::new(10).unwrap()).await;
+
+    // The verify is the only command sent so far
+    let stats = peer.stats();
+    assert_eq!(stats.packets_sent, 1);
+    assert_eq!(stats.packets_received, 0);
+    assert!(stats.bytes_sent > 0);
+    assert_eq!(stats, host.peer_stats(peer.id).unwrap());
+
+    let packet = Packet {
+        data: vec![1, 2, 3],
+        channel: 0,
+        flags: PacketFlags::reliable(),
+    };
+    peer.send(packet).await.unwrap();
+    host.poll_for_event(POLL).await.unwrap();
+    let stats = peer.stats();
+    assert_eq!(stats.packets_sent, 2);
+    assert_eq!(stats.reliable_data_in_transit, 3);
+
+    let ack = remote_ack(&host, &verify, 0xFF, 1, Duration::ZERO);
+    remote.send(&ack).await.unwrap();
+    host.poll_for_event(POLL).await.unwrap();
+    let stats = peer.stats();
+    assert_eq!(stats.packets_received, 1);
+    assert_eq!(stats.retransmits, 0);
+    assert_eq!(stats, host.peer_stats(peer.id).unwrap());
+}
+
+#[tokio::test]
+async fn retransmits_sample_their_own_round_trip() {
+    let (mut host, mut remote, mut peer, verify) =
+        connected_pair(HostConfig::new(10).unwrap()).await;
+    let info = host.peers.get_mut(&peer.id).unwrap();
+    info.round_trip_time = Duration::from_millis(20);
+    info.round_trip_time_variance = Duration::ZERO;
+    let packet = Packet {
+        data: vec![1, 2, 3],
+        channel: 0,
+        flags: PacketFlags::reliable(),
+    };
+    peer.send(packet).await.unwrap();
+    host.poll_for_event(Duration::ZERO).await.unwrap();
+
+    // The first send goes unacknowledged, only the resend is answered
+    let mut sends = Vec::new();
+    while sends.len() < 2 {
+        host.poll_for_event(Duration::from_millis(5)).await.unwrap();
+        while let Ok(command) = tokio::time::timeout(Duration::ZERO, remote.recv()).await {
+            let command = command.unwrap();
+            if let ProtocolCommand::SendReliable(_) = command.command {
+                sends.push(command.info);
+            }
+        }
+    }
+    let resend = &sends[1];
+    assert!(resend.sent_time >= sends[0].sent_time + Duration::from_millis(20));
+    let ack = remote_ack(
+        &host,
+        &verify,
+        resend.channel_id,
+        resend.reliable_sequence_number,
+        resend.sent_time,
+    );
+    remote.send(&ack).await.unwrap();
+    host.poll_for_event(POLL).await.unwrap();
+
+    let round_trip_time = host.peer_stats(peer.id).unwrap().round_trip_time;
+    assert!(
+        round_trip_time < Duration::from_millis(15),
+        "{round_trip_time:?}"
+    );
+}
+
+#[tokio::test]
+async fn reliable_commands_are_delivered_in_order() {
+    let (mut host, mut remote, mut peer, verify) =
+        connected_pair(HostConfig::new(10).unwrap()).await;
+
+    for seq in [3, 1, 2] {
+        let command = Command {
+            info: CommandInfo {
+                flags: PacketFlags::reliable(),
+                channel_id: 0,
+                reliable_sequence_number: seq,
+                ..remote_info(&host, &verify)
+            },
+            command: SendReliableCommand {
+                data: vec![seq as u8],
+            }
+            .into(),
+        };
+        remote.send(&command).await.unwrap();
+        host.poll_for_event(POLL).await.unwrap();
+    }
+
+    for seq in 1..=3 {
+        let PeerRecvEvent::Recv(packet) = peer.poll().await else {
+            panic!("packet {seq} was not delivered");
+        };
+        assert_eq!(packet.data, vec![seq]);
+    }
+}
+
+/// An unreliable fragment from the remote on its first channel
+fn remote_fragment(
+    host: &Host,
+    verify: &VerifyConnectCommand,
+    fragment: ProtocolCommand,
+) -> Command {
+    Command {
+        info: CommandInfo {
+            flags: PacketFlags {
+                unreliable_fragment: true,
+                ..Default::default()
+            },
+            channel_id: 0,
+            ..remote_info(host, verify)
+        },
+        command: fragment,
+    }
+}
+
+#[tokio::test]
+async fn unreliable_fragments_are_reassembled() {
+    let (mut host, mut remote, mut peer, verify) =
+        connected_pair(HostConfig::new(10).unwrap()).await;
+    let packet = Packet {
+        data: (0..3000).map(|i| i as u8).collect(),
+        channel: 0,
+        flags: PacketFlags::default(),
+    };
+    let fragments = host.fragment_packet(peer.id, &packet).unwrap();
+    assert_eq!(fragments.len(), 3);
+
+    // Sent back by the remote, in reverse order
+    for fragment in fragments.into_iter().rev() {
+        assert!(matches!(
+            fragment.command,
+            ProtocolCommand::SendUnreliableFragment(_)
+        ));
+        let fragment = remote_fragment(&host, &verify, fragment.command);
+        remote.send(&fragment).await.unwrap();
+        host.poll_for_event(POLL).await.unwrap();
+    }
+
+    let PeerRecvEvent::Recv(received) = peer.poll().await else {
+        panic!("packet was not reassembled");
+    };
+    assert_eq!(received.data, packet.data);
+}
+
+#[tokio::test]
+async fn fragmented_unsequenced_packets_are_sequenced() {
+    let (mut host, _remote, peer, _) = connected_pair(HostConfig::new(10).unwrap()).await;
+    let packet = Packet {
+        data: vec![0; 3000],
+        channel: 0,
+        flags: PacketFlags::unsequenced(),
+    };
+    let fragments = host.fragment_packet(peer.id, &packet).unwrap();
+    assert_eq!(fragments.len(), 3);
+
+    for fragment in fragments {
+        assert!(!fragment.info.flags.unsequenced);
+        assert!(fragment.info.flags.unreliable_fragment);
+        let ProtocolCommand::SendUnreliableFragment(fragment) = fragment.command else {
+            panic!("unsequenced packet was not sent as unreliable fragments");
+        };
+        assert_eq!(fragment.start_sequence_number, 1);
+    }
+}
+
+#[tokio::test]
+async fn newer_unreliable_commands_drop_incomplete_fragments() {
+    let (mut host, mut remote, mut peer, verify) =
+        connected_pair(HostConfig::new(10).unwrap()).await;
+    let fragment = |fragment_number: u32| {
+        SendUnreliableFragmentCommand {
+            start_sequence_number: 1,
+            fragment_count: 2,
+            fragment_number,
+            total_length: 2,
+            fragment_offset: fragment_number,
+            data: vec![fragment_number as u8],
+        }
+        .into()
+    };
+    let newer = Command {
+        info: CommandInfo {
+            channel_id: 0,
+            ..remote_info(&host, &verify)
+        },
+        command: SendUnreliableCommand {
+            unreliable_sequence_number: 3,
+            data: vec![9],
+        }
+        .into(),
+    };
+
+    for command in [
+        remote_fragment(&host, &verify, fragment(0)),
+        newer,
+        remote_fragment(&host, &verify, fragment(1)),
+    ] {
+        remote.send(&command).await.unwrap();
+        host.poll_for_event(POLL).await.unwrap();
+    }
+
+    // Only the newer packet is delivered, the group it superseded is gone
+    let PeerRecvEvent::Recv(received) = peer.poll().await else {
+        panic!("newer packet was not delivered");
+    };
+    assert_eq!(received.data, vec![9]);
+    assert!(tokio::time::timeout(POLL, peer.poll()).await.is_err());
+    let channel = host.peers[&peer.id].get_channel(0).unwrap();
+    assert!(channel.incoming_unreliable_fragments.is_empty());
+}
+
+#[tokio::test]
+async fn invalid_unreliable_fragments_are_rejected() {
+    let (mut host, mut remote, mut peer, verify) =
+        connected_pair(HostConfig::new(10).unwrap()).await;
+
+    let invalid = [
+        (1, 0, 0),
+        (2, PROTOCOL_MAXIMUM_FRAGMENT_COUNT as u32 + 1, 0),
+        // Starts past the end of the packet
+        (3, 2, 2),
+    ];
+    for (start_sequence_number, fragment_count, fragment_offset) in invalid {
+        let fragment = SendUnreliableFragmentCommand {
+            start_sequence_number,
+            fragment_count,
+            fragment_number: 0,
+            total_length: 2,
+            fragment_offset,
+            data: vec![1],
+        };
+        let fragment = remote_fragment(&host, &verify, fragment.into());
+        remote.send(&fragment).await.unwrap();
+        assert!(matches!(
+            host.poll_for_event(POLL).await,
+            Err(ENetError::InvalidPacket())
+        ));
+    }
+    assert!(tokio::time::timeout(POLL, peer.poll()).await.is_err());
+}
+
+#[tokio::test]
+async fn previous_sessions_are_ignored() {
+    let (mut host, mut remote, _, old_verify) = connected_pair(HostConfig::new(10).unwrap()).await;
+    let disconnect = Command {
+        info: CommandInfo {
+            flags: PacketFlags::reliable(),
+            reliable_sequence_number: 1,
+            ..remote_info(&host, &old_verify)
+        },
+        command: DisconnectCommand { data: 0 }.into(),
+    };
+    remote.send(&disconnect).await.unwrap();
+    let event = host.poll_for_event(POLL).await.unwrap();
+    assert!(matches!(event, HostPollEvent::Disconnect(..)));
+
+    let connect = ConnectCommand {
+        connect_id: 2,
+        ..test_connect()
+    };
+    remote.send(&connect_command(&host, connect)).await.unwrap();
+    let HostPollEvent::Connect(mut peer) = host.poll_for_event(POLL).await.unwrap() else {
+        panic!("reconnect was not accepted");
+    };
+    let (_, verify) = recv_verify(&mut remote).await;
+    assert_eq!(verify.outgoing_peer_id, old_verify.outgoing_peer_id);
+    assert_ne!(verify.outgoing_session_id, old_verify.outgoing_session_id);
+
+    let unreliable = |verify| Command {
+        info: CommandInfo {
+            channel_id: 0,
+            ..remote_info(&host, verify)
+        },
+        command: SendUnreliableCommand {
+            unreliable_sequence_number: 1,
+            data: vec![1],
+        }
+        .into(),
+    };
+    let (replayed, current) = (unreliable(&old_verify), unreliable(&verify));
+
+    // A datagram from the previous connection on the same peer id
+    remote.send(&replayed).await.unwrap();
+    host.poll_for_event(POLL).await.unwrap();
+    assert_eq!(host.peer_stats(peer.id).unwrap().packets_received, 0);
+    assert!(tokio::time::timeout(POLL, peer.poll()).await.is_err());
+
+    remote.send(&current).await.unwrap();
+    host.poll_for_event(POLL).await.unwrap();
+    assert!(matches!(peer.poll().await, PeerRecvEvent::Recv(_)));
+}
+
+#[tokio::test]
+async fn host_stats_count_unanswerable_connects() {
+    let (mut host, mut remote, _, _) = connected_pair(HostConfig::new(1).unwrap()).await;
+
+    let full = ConnectCommand {
+        connect_id: 2,
+        ..test_connect()
+    };
+    remote.send(&connect_command(&host, full)).await.unwrap();
+    assert!(matches!(
+        host.poll_for_event(POLL).await,
+        Err(ENetError::NoFreePeers)
+    ));
+
+    let invalid = ConnectCommand {
+        connect_id: 3,
+        channel_count: 0,
+        ..test_connect()
+    };
+    remote.send(&connect_command(&host, invalid)).await.unwrap();
+    assert!(matches!(
+        host.poll_for_event(POLL).await,
+        Err(ENetError::InvalidConnect(_))
+    ));
+
+    let stats = host.stats();
+    assert_eq!((stats.connects_accepted, stats.connects_refused), (1, 2));
+}
+
+#[tokio::test]
+async fn failed_sends_do_not_hold_up_other_peers() {
+    let (mut host, mut remote, peer, _) = connected_pair(HostConfig::new(10).unwrap()).await;
+    // The host's IPv4 socket cannot send to an IPv6 address
+    let unreachable = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 9000);
+    let connect = ConnectCommand {
+        connect_id: 2,
+        ..test_connect()
+    };
+    let (other, _) = host.handle_connect(unreachable, &connect).unwrap();
+
+    let remote_addr = remote.socket.local_addr().unwrap();
+    for (addr, id) in [(unreachable, other.id), (remote_addr, peer.id)] {
+        let command = Command {
+            info: CommandInfo {
+                addr,
+                flags: PacketFlags::default(),
+                internal_peer_id: id,
+                peer_id: PeerID(0),
+                channel_id: 0,
+                session_id: 0,
+                reliable_sequence_number: 0,
+                sent_time: Duration::ZERO,
+            },
+            command: SendUnreliableCommand {
+                unreliable_sequence_number: 1,
+                data: vec![id.0 as u8],
+            }
+            .into(),
+        };
+        host.send(command).await.unwrap();
+    }
+    host.poll_for_event(Duration::ZERO).await.unwrap();
+
+    loop {
+        let command = tokio::time::timeout(POLL, remote.recv())
+            .await
+            .expect("the reachable peer's command was dropped")
+            .unwrap();
+        if let ProtocolCommand::SendUnreliable(u) = command.command {
+            assert_eq!(u.data, vec![peer.id.0 as u8]);
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn packed_commands_sample_their_own_round_trip() {
+    let (mut host, mut remote, peer, verify) = connected_pair(HostConfig::new(10).unwrap()).await;
+    let info = host.peers.get_mut(&peer.id).unwrap();
+    info.round_trip_time = Duration::from_millis(20);
+    info.round_trip_time_variance = Duration::ZERO;
+
+    // The first command was made well before it is sent, as when held back by the window
+    tokio::time::sleep(Duration::from_millis(120)).await;
+    let now = host.config.start_time.elapsed();
+    let remote_addr = remote.socket.local_addr().unwrap();
+    for (seq, sent_time) in [(1, now - Duration::from_millis(100)), (2, now)] {
+        let command = Command {
+            info: CommandInfo {
+                addr: remote_addr,
+                flags: PacketFlags::reliable(),
+                internal_peer_id: peer.id,
+                peer_id: PeerID(0),
+                channel_id: 0,
+                session_id: 0,
+                reliable_sequence_number: seq,
+                sent_time,
+            },
+            command: SendReliableCommand { data: vec![1] }.into(),
+        };
+        host.send(command).await.unwrap();
+    }
+    host.poll_for_event(Duration::ZERO).await.unwrap();
+
+    // Both arrive in one datagram and are acknowledged with its sent time
+    let mut acks = Vec::new();
+    while acks.len() < 2 {
+        let command = remote.recv().await.unwrap();
+        if let ProtocolCommand::SendReliable(_) = command.command {
+            acks.push(remote_ack(
+                &host,
+                &verify,
+                0,
+                command.info.reliable_sequence_number,
+                command.info.sent_time,
+            ));
+        }
+    }
+    for ack in &acks {
+        remote.send(ack).await.unwrap();
+        host.poll_for_event(POLL).await.unwrap();
+    }
+
+    let round_trip_time = host.peer_stats(peer.id).unwrap().round_trip_time;
+    assert!(
+        round_trip_time < Duration::from_millis(15),
+        "{round_trip_time:?}"
+    );
+}
+
+#[tokio::test]
+async fn unreliable_commands_wait_for_earlier_reliable_ones() {
+    let (mut host, mut remote, mut peer, verify) =
+        connected_pair(HostConfig::new(10).unwrap()).await;
+    let info = CommandInfo {
+        channel_id: 0,
+        reliable_sequence_number: 1,
+        ..remote_info(&host, &verify)
+    };
+    let unreliable = |unreliable_sequence_number: u16| Command {
+        info: info.clone(),
+        command: SendUnreliableCommand {
+            unreliable_sequence_number,
+            data: vec![unreliable_sequence_number as u8 + 1],
+        }
+        .into(),
+    };
+    let reliable = Command {
+        info: CommandInfo {
+            flags: PacketFlags::reliable(),
+            ..info.clone()
+        },
+        command: SendReliableCommand { data: vec![1] }.into(),
+    };
+
+    // Sent after the reliable command, but arriving before it and out of order
+    for command in [unreliable(2), unreliable(1), unreliable(1), reliable] {
+        remote.send(&command).await.unwrap();
+        host.poll_for_event(POLL).await.unwrap();
+    }
+
+    for data in 1..=3 {
+        let PeerRecvEvent::Recv(packet) = peer.poll().await else {
+            panic!("packet {data} was not delivered");
+        };
+        assert_eq!(packet.data, vec![data]);
+    }
+    assert!(tokio::time::timeout(POLL, peer.poll()).await.is_err());
+}
@@ -345,3 +345,405 @@ impl Arbitrary for Data {
 
 //     Ok(())
 // }
+
+#[tokio::test]
+async fn orig_server_rewrite_cli_connect() -> Result<(), anyhow::Error> {
+    let _ = tracing_subscriber::fmt::try_init();
+    let _guard = TEST_MUTEX.get_or_init(|| Mutex::new(())).lock().await;
+
+    let serv_enet = ENET.get_or_init(|| Enet::new().context("could not initialize ENet").unwrap());
+    let mut serv_host = serv_enet
+        .create_host::<()>(
+            Some(&Address::new(Ipv4Addr::LOCALHOST, 9002)),
+            10,
+            ChannelLimit::Maximum,
+            BandwidthLimit::Unlimited,
+            BandwidthLimit::Unlimited,
+        )
+        .context("could not create host")?;
+
+    let cli_config = HostConfig::new(10)?;
+    let mut cli_host = Host::create_from_address(
+        cli_config,
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9003),
+    )
+    .await?;
+
+    let serv_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9002);
+    let mut serv_connected = false;
+    let mut cli_peer = {
+        let connect = cli_host.connect(serv_addr, 2, 42);
+        tokio::pin!(connect);
+
+        loop {
+            select! {
+                p = &mut connect => break p?,
+                _sleep = tokio::time::sleep(Duration::from_millis(1)) => {
+                    if let Some(Event::Connect(_)) = serv_host.service(0).context("service failed")? {
+                        serv_connected = true;
+                    }
+                }
+            }
+        }
+    };
+
+    // Let the server see the ack of its verify connect
+    for _ in 0..100 {
+        if serv_connected {
+            break;
+        }
+        select! {
+            e = cli_host.poll_for_event(Duration::from_millis(1)) => { e?; }
+            _sleep = tokio::time::sleep(Duration::from_millis(1)) => {}
+        }
+        if let Some(Event::Connect(_)) = serv_host.service(0).context("service failed")? {
+            serv_connected = true;
+        }
+    }
+    if !serv_connected {
+        bail!("Server never saw the connection");
+    }
+
+    cli_peer
+        .send(Packet {
+            data: b"hello".to_vec(),
+            channel: 1,
+            flags: PacketFlags::reliable(),
+        })
+        .await?;
+
+    for _ in 0..100 {
+        select! {
+            e = cli_host.poll_for_event(Duration::from_millis(1)) => { e?; }
+            _sleep = tokio::time::sleep(Duration::from_millis(1)) => {}
+        }
+        if let Some(Event::Receive {
+            channel_id,
+            ref packet,
+            ..
+        }) = serv_host.service(0).context("service failed")?
+        {
+            assert_eq!(channel_id, 1);
+            assert_eq!(packet.data(), b"hello");
+            return Ok(());
+        }
+    }
+
+    bail!("Didnt receive expected packet")
+}
+
+#[tokio::test]
+async fn server_cli_fragmented_packet() -> Result<(), anyhow::Error> {
+    let _ = tracing_subscriber::fmt::try_init();
+    let _guard = TEST_MUTEX.get_or_init(|| Mutex::new(())).lock().await;
+
+    let serv_config = HostConfig::new(10)?;
+    let mut serv_host = Host::create_from_address(
+        serv_config,
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9004),
+    )
+    .await?;
+
+    let cli_enet = ENET.get_or_init(|| Enet::new().context("could not initialize ENet").unwrap());
+    let mut cli_host = cli_enet
+        .create_host::<()>(
+            None,
+            10,
+            ChannelLimit::Maximum,
+            BandwidthLimit::Unlimited,
+            BandwidthLimit::Unlimited,
+        )
+        .context("could not create host")?;
+
+    cli_host
+        .connect(&Address::new(Ipv4Addr::LOCALHOST, 9004), 10, 0)
+        .context("connect failed")?;
+    cli_host.service(100).context("service failed")?;
+
+    let mut serv_peer = match serv_host.poll_for_event(Duration::from_millis(100)).await? {
+        HostPollEvent::Connect(p) => p,
+        e => bail!("Unexpected event {e:?}"),
+    };
+
+    let data: Vec<u8> = (0..10_000).map(|x| x as u8).collect();
+    cli_host
+        .peers()
+        .next()
+        .unwrap()
+        .send_packet(
+            orig_enet::Packet::new(&data, PacketMode::ReliableSequenced).unwrap(),
+            0,
+        )
+        .context("sending packet failed")?;
+
+    let mut got_data = false;
+    for _ in 0..100 {
+        cli_host.service(1).context("service failed")?;
+        select! {
+            e = serv_host.poll_for_event(Duration::from_millis(1)) => { e?; }
+            e = serv_peer.poll() => {
+                if let PeerRecvEvent::Recv(p) = e {
+                    assert_eq!(p.data, data);
+                    got_data = true;
+                    break;
+                }
+            }
+        }
+    }
+    if !got_data {
+        bail!("Didnt receive fragmented packet")
+    }
+
+    serv_peer
+        .send(Packet {
+            data: data.clone(),
+            channel: 0,
+            flags: PacketFlags::reliable(),
+        })
+        .await?;
+
+    for _ in 0..100 {
+        select! {
+            e = serv_host.poll_for_event(Duration::from_millis(1)) => { e?; }
+            _sleep = tokio::time::sleep(Duration::from_millis(1)) => {}
+        }
+        if let Some(Event::Receive { ref packet, .. }) =
+            cli_host.service(1).context("service failed")?
+        {
+            assert_eq!(packet.data(), &data[..]);
+            return Ok(());
+        }
+    }
+
+    bail!("Client didnt receive fragmented packet")
+}
+
+/// A client host straight from the C library, for the range coder and checksum settings the
+/// wrapper has no way to enable
+struct CHost(*mut enet_sys::ENetHost);
+
+impl CHost {
+    fn new() -> Self {
+        ENET.get_or_init(|| Enet::new().context("could not initialize ENet").unwrap());
+        let host = unsafe { enet_sys::enet_host_create(std::ptr::null(), 1, 1, 0, 0) };
+        assert!(!host.is_null());
+        Self(host)
+    }
+
+    fn with_range_coder() -> Self {
+        let host = Self::new();
+        assert_eq!(
+            unsafe { enet_sys::enet_host_compress_with_range_coder(host.0) },
+            0
+        );
+        host
+    }
+
+    fn with_checksum() -> Self {
+        let host = Self::new();
+        unsafe { (*host.0).checksum = Some(enet_sys::enet_crc32) };
+        host
+    }
+
+    fn connect(&mut self, port: u16) -> *mut enet_sys::ENetPeer {
+        let address = enet_sys::ENetAddress {
+            host: u32::from_ne_bytes(Ipv4Addr::LOCALHOST.octets()),
+            port,
+        };
+        let peer = unsafe { enet_sys::enet_host_connect(self.0, &address, 1, 0) };
+        assert!(!peer.is_null());
+        peer
+    }
+
+    fn connected(&self, peer: *mut enet_sys::ENetPeer) -> bool {
+        unsafe { (*peer).state == enet_sys::_ENetPeerState_ENET_PEER_STATE_CONNECTED }
+    }
+
+    fn send(&mut self, peer: *mut enet_sys::ENetPeer, data: &[u8]) {
+        unsafe {
+            let packet = enet_sys::enet_packet_create(
+                data.as_ptr().cast(),
+                data.len(),
+                enet_sys::_ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE,
+            );
+            assert_eq!(enet_sys::enet_peer_send(peer, 0, packet), 0);
+        }
+    }
+
+    /// Services the host once, returning the data of a packet if one was received
+    fn service(&mut self) -> Option<Vec<u8>> {
+        let mut event = std::mem::MaybeUninit::<enet_sys::ENetEvent>::zeroed();
+        let result = unsafe { enet_sys::enet_host_service(self.0, event.as_mut_ptr(), 1) };
+        assert!(result >= 0);
+        let event = unsafe { event.assume_init() };
+        if result == 0 || event.type_ != enet_sys::_ENetEventType_ENET_EVENT_TYPE_RECEIVE {
+            return None;
+        }
+
+        unsafe {
+            let packet = &*event.packet;
+            let data = std::slice::from_raw_parts(packet.data, packet.dataLength).to_vec();
+            enet_sys::enet_packet_destroy(event.packet);
+            Some(data)
+        }
+    }
+}
+
+impl Drop for CHost {
+    fn drop(&mut self) {
+        unsafe { enet_sys::enet_host_destroy(self.0) }
+    }
+}
+
+#[tokio::test]
+async fn server_cli_range_coder() -> Result<(), anyhow::Error> {
+    let _ = tracing_subscriber::fmt::try_init();
+    let _guard = TEST_MUTEX.get_or_init(|| Mutex::new(())).lock().await;
+
+    let mut serv_config = HostConfig::new(10)?;
+    serv_config.range_coder = true;
+    let mut serv_host = Host::create_from_address(
+        serv_config,
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9005),
+    )
+    .await?;
+
+    let mut cli_host = CHost::with_range_coder();
+    let cli_peer = cli_host.connect(9005);
+    cli_host.service();
+
+    let mut serv_peer = match serv_host.poll_for_event(Duration::from_millis(100)).await? {
+        HostPollEvent::Connect(p) => p,
+        e => bail!("Unexpected event {e:?}"),
+    };
+    for _ in 0..100 {
+        if cli_host.connected(cli_peer) {
+            break;
+        }
+        serv_host.poll_for_event(Duration::from_millis(1)).await?;
+        cli_host.service();
+    }
+    if !cli_host.connected(cli_peer) {
+        bail!("Client didnt connect")
+    }
+
+    // Repetitive enough to be sent compressed both ways
+    let data = b"range coder ".repeat(100);
+    serv_host.reset_stats();
+    cli_host.send(cli_peer, &data);
+
+    let mut got_data = false;
+    for _ in 0..100 {
+        cli_host.service();
+        select! {
+            e = serv_host.poll_for_event(Duration::from_millis(1)) => { e?; }
+            e = serv_peer.poll() => {
+                if let PeerRecvEvent::Recv(p) = e {
+                    assert_eq!(p.data, data);
+                    got_data = true;
+                    break;
+                }
+            }
+        }
+    }
+    if !got_data {
+        bail!("Didnt receive compressed packet")
+    }
+    assert!(serv_host.stats().socket.bytes_received < data.len() as u64);
+
+    serv_peer
+        .send(Packet {
+            data: data.clone(),
+            channel: 0,
+            flags: PacketFlags::reliable(),
+        })
+        .await?;
+
+    for _ in 0..100 {
+        select! {
+            e = serv_host.poll_for_event(Duration::from_millis(1)) => { e?; }
+            _sleep = tokio::time::sleep(Duration::from_millis(1)) => {}
+        }
+        if let Some(received) = cli_host.service() {
+            assert_eq!(received, data);
+            return Ok(());
+        }
+    }
+
+    bail!("Client didnt receive compressed packet")
+}
+
+#[tokio::test]
+async fn server_cli_checksum() -> Result<(), anyhow::Error> {
+    let _ = tracing_subscriber::fmt::try_init();
+    let _guard = TEST_MUTEX.get_or_init(|| Mutex::new(())).lock().await;
+
+    let mut serv_config = HostConfig::new(10)?;
+    serv_config.checksum = true;
+    let mut serv_host = Host::create_from_address(
+        serv_config,
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9006),
+    )
+    .await?;
+
+    let mut cli_host = CHost::with_checksum();
+    let cli_peer = cli_host.connect(9006);
+    cli_host.service();
+
+    let mut serv_peer = match serv_host.poll_for_event(Duration::from_millis(100)).await? {
+        HostPollEvent::Connect(p) => p,
+        e => bail!("Unexpected event {e:?}"),
+    };
+    for _ in 0..100 {
+        if cli_host.connected(cli_peer) {
+            break;
+        }
+        serv_host.poll_for_event(Duration::from_millis(1)).await?;
+        cli_host.service();
+    }
+    if !cli_host.connected(cli_peer) {
+        bail!("Client didnt connect")
+    }
+
+    let data = b"checksummed".to_vec();
+    cli_host.send(cli_peer, &data);
+
+    let mut got_data = false;
+    for _ in 0..100 {
+        cli_host.service();
+        select! {
+            e = serv_host.poll_for_event(Duration::from_millis(1)) => { e?; }
+            e = serv_peer.poll() => {
+                if let PeerRecvEvent::Recv(p) = e {
+                    assert_eq!(p.data, data);
+                    got_data = true;
+                    break;
+                }
+            }
+        }
+    }
+    if !got_data {
+        bail!("Didnt receive checksummed packet")
+    }
+
+    serv_peer
+        .send(Packet {
+            data: data.clone(),
+            channel: 0,
+            flags: PacketFlags::reliable(),
+        })
+        .await?;
+
+    for _ in 0..100 {
+        select! {
+            e = serv_host.poll_for_event(Duration::from_millis(1)) => { e?; }
+            _sleep = tokio::time::sleep(Duration::from_millis(1)) => {}
+        }
+        if let Some(received) = cli_host.service() {
+            assert_eq!(received, data);
+            return Ok(());
+        }
+    }
+
+    bail!("Client didnt receive checksummed packet")
+}